@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use regex::Regex;
+
+use termsearch::history::{
+    map_history_tail, read_history_from, seek_history_tail, HistoryFormat, Shell,
+};
+
+/// Number of entries kept from the history file, the default maximum plus the reorder margin.
+const NUM_LINES: usize = 11_000;
+
+/// Generate the line of the `i`th entry of a ZSH extended history.
+fn history_line(i: u64) -> String {
+    format!(
+        ": {}:{};git commit -m 'change number {}' && cargo test --release\n",
+        1_600_000_000 + i,
+        i % 7,
+        i
+    )
+}
+
+/// Write a ZSH extended history file of at least `size` bytes.
+///
+/// # Arguments
+///
+/// * `path`: The path to the history file.
+/// * `size`: The minimum size of the file in bytes.
+///
+fn write_history(path: &Path, size: u64) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    let mut written = 0;
+    let mut i: u64 = 0;
+    while written < size {
+        let line = history_line(i);
+        writer.write_all(line.as_bytes()).unwrap();
+        written += line.len() as u64;
+        i += 1;
+    }
+    writer.flush().unwrap();
+}
+
+/// Parse the end of a 100MB history file, mapped into memory and read
+/// through a buffered reader.
+fn tail_100mb(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("zsh_history");
+    let size = 100 * 1024 * 1024;
+    write_history(&path, size);
+
+    let mut group = c.benchmark_group("tail_100mb");
+    group.sample_size(10);
+    // The bytes first read for the default number of entries, and the whole file
+    for limit in [NUM_LINES as u64 * 128, size * 2] {
+        let label = if limit < size { "tail" } else { "whole" };
+        group.bench_with_input(BenchmarkId::new("mapped", label), &limit, |b, &limit| {
+            b.iter(|| {
+                let (tail, _) = map_history_tail(&path, Some(limit)).unwrap();
+                read_history_from(tail, Shell::Zsh, NUM_LINES, None, Utc::now()).unwrap()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("buffered", label), &limit, |b, &limit| {
+            b.iter(|| {
+                let (tail, _) = seek_history_tail(&path, limit).unwrap();
+                read_history_from(black_box(tail), Shell::Zsh, NUM_LINES, None, Utc::now()).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Parse 100k ZSH extended history lines split by hand, and with the regex
+/// they were split with before.
+fn zsh_lines_100k(c: &mut Criterion) {
+    let input: String = (0..100_000).map(history_line).collect();
+    let format = HistoryFormat::new(
+        Regex::new(r"^: (?P<ts>\d+):\d+;(?P<cmd>.*)$").unwrap(),
+        None,
+    );
+
+    let mut group = c.benchmark_group("zsh_lines_100k");
+    group.bench_function("by_hand", |b| {
+        b.iter(|| {
+            read_history_from(
+                black_box(input.as_bytes()),
+                Shell::Zsh,
+                usize::MAX,
+                None,
+                Utc::now(),
+            )
+            .unwrap()
+        })
+    });
+    group.bench_function("regex", |b| {
+        b.iter(|| {
+            read_history_from(
+                black_box(input.as_bytes()),
+                Shell::Zsh,
+                usize::MAX,
+                Some(format.clone()),
+                Utc::now(),
+            )
+            .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tail_100mb, zsh_lines_100k);
+criterion_main!(benches);
@@ -0,0 +1,187 @@
+use chrono::{TimeDelta, Utc};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use termsearch::history::CommandEntry;
+use termsearch::search::{SearchCandidates, SearchIndex, SearchOptions};
+
+/// Command names the generated commands start with.
+const TOOLS: &[&str] = &[
+    "git", "docker", "kubectl", "cargo", "ls", "cd", "ssh", "grep", "vim", "make", "npm", "python",
+];
+
+/// Arguments following the command names of the generated commands.
+const ARGS: &[&str] = &[
+    "status",
+    "commit -m 'fix typo'",
+    "push origin main",
+    "compose up -d",
+    "get pods -n kube-system",
+    "build --release",
+    "-la",
+    "~/projects/termsearch",
+    "deploy@staging.example.com",
+    "-rn TODO src",
+    "src/main.rs",
+    "test -- --nocapture",
+];
+
+/// Generate a history of commands run a minute apart, oldest first.
+///
+/// # Arguments
+///
+/// * `len`: The number of entries.
+/// * `unique`: The number of distinct commands the entries are drawn from.
+///
+fn history(len: usize, unique: usize) -> Vec<CommandEntry> {
+    let now = Utc::now();
+    let mut seed: u64 = 42;
+    (0..len)
+        .map(|i| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let k = (seed >> 33) as usize % unique;
+            let tool = TOOLS[k % TOOLS.len()];
+            let arg = ARGS[(k / TOOLS.len()) % ARGS.len()];
+            CommandEntry {
+                command: format!("{} {} {}", tool, arg, k),
+                timestamp: now - TimeDelta::minutes((len - i) as i64),
+                source: None,
+                metadata: None,
+                count: 1,
+            }
+        })
+        .collect()
+}
+
+/// Filter a 50k entry history, as each keystroke does.
+fn filter(c: &mut Criterion) {
+    let options = SearchOptions::default();
+    let history = history(50_000, 20_000);
+    let index = SearchIndex::new(&history, &options);
+
+    let mut group = c.benchmark_group("filter_50k");
+    for term in ["g", "dock", "git push", "kubectl get pods", "gtpsh"] {
+        group.bench_with_input(BenchmarkId::from_parameter(term), term, |b, term| {
+            b.iter(|| index.search(black_box(term), 100, &options))
+        });
+    }
+    group.finish();
+}
+
+/// Build the index of a history, done once when the history is loaded.
+fn build_index(c: &mut Criterion) {
+    let options = SearchOptions::default();
+    let mut group = c.benchmark_group("index");
+    for len in [10_000, 50_000, 100_000] {
+        let history = history(len, len / 3);
+        group.bench_with_input(BenchmarkId::from_parameter(len), &history, |b, history| {
+            b.iter(|| SearchIndex::new(black_box(history), &options))
+        });
+    }
+    group.finish();
+}
+
+/// Type a term one character at a time on a 100k entry history, searching
+/// after each keystroke as the UI does.
+fn keystrokes(c: &mut Criterion) {
+    let options = SearchOptions::default();
+    let history = history(100_000, 30_000);
+    let index = SearchIndex::new(&history, &options);
+    let term = "docker compose";
+
+    let mut group = c.benchmark_group("keystrokes_100k");
+    group.bench_function("narrowed", |b| {
+        b.iter(|| {
+            let mut candidates: Option<SearchCandidates> = None;
+            for end in 1..=term.len() {
+                black_box(index.narrow_search(&term[..end], 100, &options, &mut candidates));
+            }
+        })
+    });
+    group.bench_function("full", |b| {
+        b.iter(|| {
+            for end in 1..=term.len() {
+                black_box(index.search(&term[..end], 100, &options));
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Score histories serially and in parallel, to choose the number of unique
+/// commands from which scoring is parallel.
+fn parallel_scoring(c: &mut Criterion) {
+    let serial = SearchOptions {
+        parallel_threshold: usize::MAX,
+        ..SearchOptions::default()
+    };
+    let parallel = SearchOptions {
+        parallel_threshold: 0,
+        ..SearchOptions::default()
+    };
+
+    let mut group = c.benchmark_group("scoring");
+    group.sample_size(10);
+    for len in [1_000, 5_000, 10_000, 20_000, 100_000, 500_000] {
+        let history = history(len, len);
+        let index = SearchIndex::new(&history, &serial);
+        group.bench_with_input(BenchmarkId::new("serial", len), &index, |b, index| {
+            b.iter(|| index.search(black_box("git"), 100, &serial))
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", len), &index, |b, index| {
+            b.iter(|| index.search(black_box("git"), 100, &parallel))
+        });
+    }
+    group.finish();
+}
+
+/// Search a 100k entry history for a term most commands match, so that most
+/// commands are scored and ranked.
+fn common_query(c: &mut Criterion) {
+    let options = SearchOptions::default();
+    let history = history(100_000, 33_000);
+    let index = SearchIndex::new(&history, &options);
+
+    let mut group = c.benchmark_group("common_query_100k");
+    group.bench_function("search", |b| {
+        b.iter(|| index.search(black_box("s"), 100, &options))
+    });
+    group.bench_function("frequent", |b| {
+        b.iter(|| index.frequent(black_box(100), &options))
+    });
+    group.finish();
+}
+
+/// Narrow down the commands matching a term most commands match, to choose
+/// how many matching commands are kept to narrow down the next search.
+fn narrowing(c: &mut Criterion) {
+    let options = SearchOptions::default();
+    let mut group = c.benchmark_group("narrowing");
+    group.sample_size(10);
+    for len in [10_000, 100_000, 200_000] {
+        let history = history(len, len);
+        let index = SearchIndex::new(&history, &options);
+        let mut matching = None;
+        index.narrow_search("s", 100, &options, &mut matching);
+
+        group.bench_with_input(BenchmarkId::new("narrowed", len), &index, |b, index| {
+            b.iter(|| index.narrow_search(black_box("st"), 100, &options, &mut matching.clone()))
+        });
+        group.bench_with_input(BenchmarkId::new("full", len), &index, |b, index| {
+            b.iter(|| index.search(black_box("st"), 100, &options))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    filter,
+    build_index,
+    keystrokes,
+    parallel_scoring,
+    common_query,
+    narrowing
+);
+criterion_main!(benches);
@@ -0,0 +1,187 @@
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::debug;
+
+/// Read the aliases dumped by `alias -L` in ZSH.
+///
+/// # Arguments
+///
+/// * `path`: The path to the file holding the output of `alias -L`.
+///
+/// # Returns
+///
+/// The aliases, mapped to the text they expand to.
+///
+pub fn read_aliases(path: &Path) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read aliases file {:?}", path))?;
+    let aliases = parse_aliases(&text);
+    debug!("Read {} aliases from {}", aliases.len(), path.display());
+    Ok(aliases)
+}
+
+/// Parse the output of `alias -L` in ZSH.
+///
+/// Global (`-g`) and suffix (`-s`) aliases are skipped, since only the first
+/// word of a command is expanded, as are lines that are not alias definitions.
+///
+/// # Arguments
+///
+/// * `text`: The output of `alias -L`.
+///
+/// # Returns
+///
+/// The aliases, mapped to the text they expand to.
+///
+pub fn parse_aliases(text: &str) -> HashMap<String, String> {
+    text.lines()
+        .filter_map(|line| {
+            let definition = line.trim().strip_prefix("alias ")?.trim_start();
+            let definition = match definition.strip_prefix("-- ") {
+                Some(definition) => definition,
+                None if definition.starts_with('-') => return None,
+                None => definition,
+            };
+            let (name, value) = definition.split_once('=')?;
+            let name = unquote(name);
+            (!name.is_empty()).then(|| (name, unquote(value)))
+        })
+        .collect()
+}
+
+/// Remove the shell quoting of a word.
+///
+/// # Arguments
+///
+/// * `word`: The word, with single quotes, double quotes or backslashes.
+///
+/// # Returns
+///
+/// The word as the shell reads it.
+///
+fn unquote(word: &str) -> String {
+    let mut unquoted = String::with_capacity(word.len());
+    let mut chars = word.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => unquoted.extend(chars.by_ref().take_while(|&c| c != '\'')),
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => unquoted.extend(chars.next()),
+                        c => unquoted.push(c),
+                    }
+                }
+            }
+            '\\' => unquoted.extend(chars.next()),
+            c => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+/// Expand the alias of the first word of a command.
+///
+/// As in ZSH, the first word of the expansion is expanded again, unless it is
+/// an alias already expanded, so that self-referential aliases such as
+/// `ls='ls --color'` terminate.
+///
+/// # Arguments
+///
+/// * `command`: The command to expand.
+/// * `aliases`: The aliases, mapped to the text they expand to.
+///
+/// # Returns
+///
+/// The command with its first word expanded, or the command itself if it is not an alias.
+///
+pub fn expand_alias<'a>(command: &'a str, aliases: &HashMap<String, String>) -> Cow<'a, str> {
+    let mut expanded = Cow::Borrowed(command);
+    if aliases.is_empty() {
+        return expanded;
+    }
+
+    let mut seen = HashSet::new();
+    loop {
+        let trimmed = expanded.trim_start();
+        let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+        let (name, rest) = trimmed.split_at(end);
+        let Some(value) = aliases.get(name) else {
+            break;
+        };
+        if !seen.insert(name.to_string()) {
+            break;
+        }
+        expanded = Cow::Owned(format!("{}{}", value, rest));
+    }
+
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build aliases from their names and expansions.
+    fn alias_map(definitions: &[(&str, &str)]) -> HashMap<String, String> {
+        definitions
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_quoted_definitions() {
+        let text = "alias -- -='cd -'\n\
+                    alias gst='git status'\n\
+                    alias ll=\"ls -l \\\"\\$@\\\"\"\n\
+                    alias -g G='| grep'\n\
+                    alias -s txt=vim\n\
+                    alias l='ls -CF'\\''x'\\'''\n\
+                    not an alias\n";
+        assert_eq!(
+            parse_aliases(text),
+            alias_map(&[
+                ("-", "cd -"),
+                ("gst", "git status"),
+                ("ll", "ls -l \"$@\""),
+                ("l", "ls -CF'x'"),
+            ])
+        );
+    }
+
+    #[test]
+    fn expand_alias_chains() {
+        let aliases = alias_map(&[("g", "git"), ("gst", "g status"), ("k", "kubectl")]);
+        assert_eq!(expand_alias("gst -s", &aliases), "git status -s");
+        assert_eq!(expand_alias("k get pods", &aliases), "kubectl get pods");
+        // Only the first word is expanded
+        assert_eq!(expand_alias("sudo k get pods", &aliases), "sudo k get pods");
+        assert!(matches!(
+            expand_alias("make", &aliases),
+            Cow::Borrowed("make")
+        ));
+    }
+
+    #[test]
+    fn stop_expanding_recursive_aliases() {
+        // An alias expanding to itself is expanded once
+        let aliases = alias_map(&[("ls", "ls -G"), ("ll", "ls -l")]);
+        assert_eq!(expand_alias("ls /tmp", &aliases), "ls -G /tmp");
+        assert_eq!(expand_alias("ll", &aliases), "ls -G -l");
+
+        // Mutually recursive aliases stop at the first alias seen again
+        let aliases = alias_map(&[("a", "b -x"), ("b", "c -y"), ("c", "a -z")]);
+        assert_eq!(expand_alias("a", &aliases), "a -z -y -x");
+        assert_eq!(expand_alias("b 1", &aliases), "b -x -z -y 1");
+        let aliases = alias_map(&[("yes", "yes")]);
+        assert_eq!(expand_alias("yes", &aliases), "yes");
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use chrono::{TimeZone, Utc};
+use log::debug;
+
+use crate::datastore::{escape, unescape};
+use crate::history::{CommandEntry, CommandMetadata, Shell, SourceLocation};
+
+/// First line of cache files, changed whenever their format changes.
+const CACHE_VERSION: &str = "termsearch-cache 2";
+
+/// Number of bytes before the cached offset compared to check that the
+/// history file was only appended to.
+const CHECK_BYTES: u64 = 64;
+
+/// The parsed entries of a history file, with the state of the file when it was parsed.
+pub struct HistoryCache {
+    /// The number of bytes parsed.
+    pub offset: u64,
+    /// The modification time of the history file, in nanoseconds since the Unix epoch.
+    pub modified: u128,
+    /// The bytes preceding the parsed offset.
+    pub check: Vec<u8>,
+    /// The last entries parsed, oldest first.
+    pub entries: VecDeque<CommandEntry>,
+}
+
+/// Identify a cached history by the history file and the way it was parsed.
+pub struct CacheKey<'a> {
+    /// The path to the history file.
+    pub path: &'a Path,
+    /// The shell that wrote the history file.
+    pub shell: Shell,
+    /// The maximum number of entries kept.
+    pub num_lines: usize,
+}
+
+impl CacheKey<'_> {
+    /// Describe the key, as written in the cache file.
+    fn describe(&self) -> String {
+        format!(
+            "{}\t{:?}\t{}",
+            escape(&self.path.to_string_lossy()),
+            self.shell,
+            self.num_lines
+        )
+    }
+
+    /// Get the path of the cache file.
+    fn cache_file(&self) -> Result<PathBuf> {
+        let dir = match env::var("XDG_CACHE_HOME") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(env::var("HOME").context("HOME environment variable not set")?)
+                .join(".cache"),
+        };
+        Ok(dir.join("termsearch").join(self.file_name()))
+    }
+
+    /// Get the name of the cache file, which stays the same across builds.
+    fn file_name(&self) -> String {
+        format!("{:016x}.cache", fnv1a(self.describe().as_bytes()))
+    }
+}
+
+/// Hash bytes with 64-bit FNV-1a, whose result does not depend on the Rust release.
+///
+/// # Arguments
+///
+/// * `bytes`: The bytes to hash.
+///
+/// # Returns
+///
+/// The hash of the bytes.
+///
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Create a directory and its parents, only accessible by the current user.
+///
+/// # Arguments
+///
+/// * `dir`: The path to the directory.
+///
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        builder.mode(0o700);
+        builder.create(dir)?;
+        // Tighten a directory created by an earlier version
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(not(unix))]
+    builder.create(dir)
+}
+
+/// Create or truncate a file only readable by the current user.
+///
+/// # Arguments
+///
+/// * `path`: The path to the file.
+///
+/// # Returns
+///
+/// The file, opened for writing.
+///
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // A file left by an interrupted save keeps its permissions when truncated
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(file)
+    }
+    #[cfg(not(unix))]
+    options.open(path)
+}
+
+/// Get the modification time of a file, in nanoseconds since the Unix epoch.
+///
+/// # Arguments
+///
+/// * `path`: The path to the file.
+///
+pub fn modified_nanos(path: &Path) -> io::Result<u128> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos())
+}
+
+/// Read the bytes of a file preceding an offset.
+///
+/// # Arguments
+///
+/// * `path`: The path to the file.
+/// * `offset`: The offset the bytes end at.
+///
+/// # Returns
+///
+/// Up to `CHECK_BYTES` bytes ending at the offset.
+///
+pub fn check_bytes(path: &Path, offset: u64) -> io::Result<Vec<u8>> {
+    let start = offset.saturating_sub(CHECK_BYTES);
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut check = Vec::new();
+    file.take(offset - start).read_to_end(&mut check)?;
+    Ok(check)
+}
+
+/// Load the cached history of a history file.
+///
+/// # Arguments
+///
+/// * `key`: The key of the cached history.
+///
+/// # Returns
+///
+/// The cached history, or `None` if there is none or it cannot be read.
+///
+pub fn load(key: &CacheKey) -> Option<HistoryCache> {
+    let path = key.cache_file().ok()?;
+    let file = File::open(&path).ok()?;
+
+    match parse_cache(BufReader::new(file), key) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            debug!("Ignore history cache {:?}: {:#}", path, e);
+            None
+        }
+    }
+}
+
+/// Parse a cache file.
+///
+/// # Arguments
+///
+/// * `reader`: The reader over the cache file.
+/// * `key`: The expected key of the cached history.
+///
+fn parse_cache(reader: impl BufRead, key: &CacheKey) -> Result<HistoryCache> {
+    let mut lines = reader.lines();
+    let mut next_line = || -> Result<String> { Ok(lines.next().context("truncated cache")??) };
+
+    if next_line()? != CACHE_VERSION {
+        anyhow::bail!("unsupported cache version");
+    }
+    if next_line()? != key.describe() {
+        anyhow::bail!("cache of another history");
+    }
+    let state = next_line()?;
+    let [offset, modified, check] = state.split('\t').collect::<Vec<_>>()[..] else {
+        anyhow::bail!("invalid cache state");
+    };
+
+    let mut cache = HistoryCache {
+        offset: offset.parse()?,
+        modified: modified.parse()?,
+        check: decode_hex(check).context("invalid check bytes")?,
+        entries: VecDeque::new(),
+    };
+    for line in lines {
+        cache
+            .entries
+            .push_back(parse_entry(&line?).context("invalid cache entry")?);
+    }
+
+    Ok(cache)
+}
+
+/// Save the parsed history of a history file, replacing its previous cache.
+///
+/// The cache holds commands in plain text, so it is only readable by the
+/// current user, like the history file.
+///
+/// # Arguments
+///
+/// * `key`: The key of the cached history.
+/// * `cache`: The history to cache.
+///
+pub fn save(key: &CacheKey, cache: &HistoryCache) -> Result<()> {
+    write_cache(&key.cache_file()?, key, cache)
+}
+
+/// Write the parsed history of a history file to a cache file.
+///
+/// # Arguments
+///
+/// * `path`: The path to the cache file.
+/// * `key`: The key of the cached history.
+/// * `cache`: The history to cache.
+///
+fn write_cache(path: &Path, key: &CacheKey, cache: &HistoryCache) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        create_private_dir(dir)?;
+    }
+
+    // Write to a temporary file first, so that readers never see a partial cache
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(create_private_file(&temp_path)?);
+    writeln!(writer, "{}", CACHE_VERSION)?;
+    writeln!(writer, "{}", key.describe())?;
+    writeln!(
+        writer,
+        "{}\t{}\t{}",
+        cache.offset,
+        cache.modified,
+        encode_hex(&cache.check)
+    )?;
+    for entry in &cache.entries {
+        writeln!(writer, "{}", format_entry(entry))?;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&temp_path, path)?;
+
+    debug!(
+        "Saved {} entries to history cache {:?}",
+        cache.entries.len(),
+        path
+    );
+    Ok(())
+}
+
+/// Format an entry as a line of the cache file.
+///
+/// The fields are the timestamp, the duration, the offset and length of the
+/// entry in the history file, and the command.
+fn format_entry(entry: &CommandEntry) -> String {
+    let duration = entry
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.duration)
+        .map(|duration| duration.as_secs().to_string())
+        .unwrap_or_default();
+    let source = entry
+        .source
+        .map(|source| format!("{}\t{}", source.offset, source.len))
+        .unwrap_or_else(|| "\t".to_string());
+
+    format!(
+        "{}\t{}\t{}\t{}",
+        entry.timestamp.timestamp(),
+        duration,
+        source,
+        escape(&entry.command)
+    )
+}
+
+/// Parse a line of the cache file.
+fn parse_entry(line: &str) -> Option<CommandEntry> {
+    let [timestamp, duration, offset, len, command] = line.split('\t').collect::<Vec<_>>()[..]
+    else {
+        return None;
+    };
+
+    let source = match offset {
+        "" => None,
+        _ => Some(SourceLocation {
+            offset: offset.parse().ok()?,
+            len: len.parse().ok()?,
+        }),
+    };
+    let metadata = match duration {
+        "" => None,
+        _ => Some(CommandMetadata {
+            duration: Some(Duration::from_secs(duration.parse().ok()?)),
+            ..Default::default()
+        }),
+    };
+
+    Some(CommandEntry {
+        command: unescape(command),
+        timestamp: Utc.timestamp_opt(timestamp.parse().ok()?, 0).single()?,
+        source,
+        metadata,
+        count: 1,
+    })
+}
+
+/// Encode bytes as lowercase hexadecimal.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode lowercase hexadecimal into bytes.
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(path: &Path) -> CacheKey<'_> {
+        CacheKey {
+            path,
+            shell: Shell::Zsh,
+            num_lines: 11000,
+        }
+    }
+
+    #[test]
+    fn name_cache_files_stably() {
+        // The name must not change with the Rust release, or caches would be orphaned
+        let name = key(Path::new("/home/user/.zsh_history")).file_name();
+        assert_eq!(name, "2e80cfe640d2fe8d.cache");
+        assert_ne!(name, key(Path::new("/home/user/.bash_history")).file_name());
+    }
+
+    #[test]
+    fn save_and_load_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("termsearch").join("history.cache");
+        let history = Path::new("/home/user/.zsh_history");
+        let entry = CommandEntry {
+            command: "echo 'a\tb'\nls".to_string(),
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            source: Some(SourceLocation {
+                offset: 40,
+                len: 30,
+            }),
+            metadata: None,
+            count: 1,
+        };
+        let cache = HistoryCache {
+            offset: 70,
+            modified: 1,
+            check: vec![0x0a, 0xff],
+            entries: VecDeque::from([entry]),
+        };
+        write_cache(&path, &key(history), &cache).unwrap();
+
+        let loaded =
+            parse_cache(BufReader::new(File::open(&path).unwrap()), &key(history)).unwrap();
+        assert_eq!((loaded.offset, loaded.check), (70, vec![0x0a, 0xff]));
+        assert_eq!(loaded.entries[0].command, cache.entries[0].command);
+        assert_eq!(loaded.entries[0].source, cache.entries[0].source);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_cache_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("termsearch");
+        fs::create_dir(&cache_dir).unwrap();
+        fs::set_permissions(&cache_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let path = cache_dir.join("history.cache");
+        let cache = HistoryCache {
+            offset: 0,
+            modified: 0,
+            check: Vec::new(),
+            entries: VecDeque::new(),
+        };
+        write_cache(&path, &key(Path::new("history")), &cache).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&cache_dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+    }
+}
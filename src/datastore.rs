@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::debug;
+
+use crate::error::HistoryError;
+use crate::history::{data_dir, CommandEntry};
+
+/// Seconds by which records may be appended out of order, as the shell hook
+/// records commands in the background.
+const RECORD_SKEW: i64 = 60;
+
+/// A command recorded by the shell hook, with the context it was run in.
+pub struct Record {
+    /// The command as typed.
+    pub command: String,
+    /// When the command was run.
+    pub timestamp: DateTime<Utc>,
+    /// The working directory the command was run in (optional).
+    pub cwd: Option<String>,
+    /// The terminal session the command was run in (optional).
+    pub session: Option<String>,
+    /// The host the command was run on (optional).
+    pub hostname: Option<String>,
+}
+
+impl Record {
+    /// Create a new `Record` of a command run now on this host.
+    ///
+    /// # Arguments
+    ///
+    /// * `command`: The command as typed.
+    /// * `cwd`: The working directory the command was run in (optional).
+    /// * `session`: The terminal session the command was run in (optional).
+    ///
+    pub fn new(command: String, cwd: Option<String>, session: Option<String>) -> Self {
+        Self {
+            command,
+            timestamp: Utc::now(),
+            cwd,
+            session,
+            hostname: hostname(),
+        }
+    }
+}
+
+/// Get the path of the datastore file.
+fn datastore_file() -> Result<PathBuf> {
+    Ok(data_dir()?.join("termsearch").join("history.tsv"))
+}
+
+/// Get the name of this host.
+fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
+/// Append a record to the datastore.
+///
+/// Records are written as a line of tab-separated fields: the Unix timestamp,
+/// the hostname, the session, the working directory and the command, with
+/// tabs, line breaks and backslashes escaped. The datastore is only readable
+/// by the current user, like the history file.
+///
+/// # Arguments
+///
+/// * `record`: The record to append.
+///
+pub fn add_record(record: &Record) -> Result<()> {
+    append_record(&datastore_file()?, record)
+}
+
+/// Append a record to a datastore file.
+///
+/// # Arguments
+///
+/// * `path`: The path to the datastore file.
+/// * `record`: The record to append.
+///
+fn append_record(path: &Path, record: &Record) -> Result<()> {
+    let io_error = |source| HistoryError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(io_error)?;
+    }
+
+    let fields = [
+        record.timestamp.timestamp().to_string(),
+        record.hostname.as_deref().map(escape).unwrap_or_default(),
+        record.session.as_deref().map(escape).unwrap_or_default(),
+        record.cwd.as_deref().map(escape).unwrap_or_default(),
+        escape(&record.command),
+    ];
+
+    // A single write keeps lines whole when several shells append at once
+    let mut options = OpenOptions::new();
+    options.append(true).create(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(io_error)?;
+    file.write_all(format!("{}\n", fields.join("\t")).as_bytes())
+        .map_err(io_error)?;
+
+    debug!("Recorded command in datastore {:?}", path);
+    Ok(())
+}
+
+/// Add the working directories recorded in the datastore to history entries.
+///
+/// An entry matches a record with the same command run within a second of it,
+/// as the shell and the hook may not read the clock at the same time. Entries
+/// that already have a working directory are left untouched.
+///
+/// # Arguments
+///
+/// * `history`: The history entries to annotate.
+///
+pub fn annotate(history: &mut [CommandEntry]) -> Result<()> {
+    annotate_from(&datastore_file()?, history)
+}
+
+/// Add the working directories recorded in a datastore file to history entries.
+///
+/// Records are appended in the order commands are run, so only the records
+/// from around the oldest entry to annotate are read.
+///
+/// # Arguments
+///
+/// * `path`: The path to the datastore file.
+/// * `history`: The history entries to annotate.
+///
+fn annotate_from(path: &Path, history: &mut [CommandEntry]) -> Result<()> {
+    let needs_cwd = |entry: &CommandEntry| {
+        entry
+            .metadata
+            .as_ref()
+            .is_none_or(|metadata| metadata.cwd.is_none())
+    };
+    let Some(oldest) = history
+        .iter()
+        .filter(|entry| needs_cwd(entry))
+        .map(|entry| entry.timestamp.timestamp())
+        .min()
+    else {
+        return Ok(());
+    };
+    let Ok(file) = fs::File::open(path) else {
+        return Ok(());
+    };
+    let io_error = |source| HistoryError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    // Skip the records older than the history entries
+    let len = file.metadata().map_err(io_error)?.len();
+    let mut reader = BufReader::new(file);
+    let start = first_record_since(&mut reader, len, oldest - 1 - RECORD_SKEW).map_err(io_error)?;
+    reader.seek(SeekFrom::Start(start)).map_err(io_error)?;
+    debug!("Read datastore from offset {} of {}", start, len);
+
+    // Index the recorded directories by timestamp and command
+    let mut directories: HashMap<(i64, String), String> = HashMap::new();
+    for line in reader.lines() {
+        let line = line.map_err(io_error)?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [timestamp, _hostname, _session, cwd, command] = fields[..] else {
+            debug!("Skip malformed datastore record");
+            continue;
+        };
+        if let (Ok(timestamp), false) = (timestamp.parse(), cwd.is_empty()) {
+            directories.insert((timestamp, unescape(command)), unescape(cwd));
+        }
+    }
+
+    let mut annotated = 0;
+    for entry in history.iter_mut() {
+        if !needs_cwd(entry) {
+            continue;
+        }
+
+        let mut key = (0, entry.command.clone());
+        let timestamp = entry.timestamp.timestamp();
+        let cwd = [timestamp, timestamp - 1, timestamp + 1]
+            .into_iter()
+            .find_map(|timestamp| {
+                key.0 = timestamp;
+                directories.get(&key)
+            });
+        if let Some(cwd) = cwd {
+            entry.metadata.get_or_insert_with(Default::default).cwd = Some(cwd.clone());
+            annotated += 1;
+        }
+    }
+
+    debug!("Annotated {} history entries from the datastore", annotated);
+    Ok(())
+}
+
+/// Find the first record of the datastore run at or after a time, by binary
+/// search over the records, which are appended in the order they are run.
+///
+/// # Arguments
+///
+/// * `reader`: The reader over the datastore.
+/// * `len`: The length of the datastore in bytes.
+/// * `since`: The Unix timestamp records are looked for from.
+///
+/// # Returns
+///
+/// The offset of the first record at or after `since`, or the length of the
+/// datastore if there is none. Malformed records are considered recent, so
+/// that they never hide the records after them.
+///
+fn first_record_since(reader: &mut (impl BufRead + Seek), len: u64, since: i64) -> io::Result<u64> {
+    let mut line = Vec::new();
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let start = record_start(reader, middle)?;
+
+        line.clear();
+        reader.read_until(b'\n', &mut line)?;
+        let timestamp = line
+            .split(|&byte| byte == b'\t')
+            .next()
+            .and_then(|field| std::str::from_utf8(field).ok()?.parse::<i64>().ok());
+        if start >= len || timestamp.is_none_or(|timestamp| timestamp >= since) {
+            high = middle;
+        } else {
+            low = middle + 1;
+        }
+    }
+    record_start(reader, low)
+}
+
+/// Move a reader to the start of the first record at or after an offset.
+///
+/// # Arguments
+///
+/// * `reader`: The reader over the datastore.
+/// * `offset`: The offset to look from.
+///
+/// # Returns
+///
+/// The offset of the record, or the end of the datastore if there is none.
+///
+fn record_start(reader: &mut (impl BufRead + Seek), offset: u64) -> io::Result<u64> {
+    if offset == 0 {
+        return reader.seek(SeekFrom::Start(0));
+    }
+    // A record starts after a newline, which may be the byte just before the offset
+    reader.seek(SeekFrom::Start(offset - 1))?;
+    let skipped = reader.skip_until(b'\n')?;
+    Ok(offset - 1 + skipped as u64)
+}
+
+/// Escape tabs, line breaks and backslashes in a datastore field.
+pub fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+/// Decode the escape sequences of a datastore field.
+pub fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record(timestamp: i64, cwd: &str, command: &str) -> Record {
+        Record {
+            command: command.to_string(),
+            timestamp: Utc.timestamp_opt(timestamp, 0).unwrap(),
+            cwd: Some(cwd.to_string()),
+            session: Some("1".to_string()),
+            hostname: None,
+        }
+    }
+
+    fn entry(timestamp: i64, command: &str) -> CommandEntry {
+        CommandEntry {
+            command: command.to_string(),
+            timestamp: Utc.timestamp_opt(timestamp, 0).unwrap(),
+            source: None,
+            metadata: None,
+            count: 1,
+        }
+    }
+
+    fn cwd(entry: &CommandEntry) -> Option<&str> {
+        entry.metadata.as_ref()?.cwd.as_deref()
+    }
+
+    #[test]
+    fn annotate_recorded_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.tsv");
+        append_record(&path, &record(1000, "/srv", "make\tall")).unwrap();
+        append_record(&path, &record(2001, "/home/user", "ls")).unwrap();
+
+        let mut history = [
+            entry(1000, "make\tall"),
+            entry(2000, "ls"),
+            entry(3000, "pwd"),
+        ];
+        annotate_from(&path, &mut history).unwrap();
+
+        assert_eq!(cwd(&history[0]), Some("/srv"));
+        assert_eq!(cwd(&history[1]), Some("/home/user"));
+        assert_eq!(cwd(&history[2]), None);
+    }
+
+    #[test]
+    fn find_records_since() {
+        let records: String = (0..100)
+            .map(|i| format!("{}\thost\t1\t/dir{}\tcmd{}\n", 1000 + i * 10, i, i))
+            .collect();
+        let last = records.trim_end().rfind('\n').unwrap() as u64 + 1;
+        let len = records.len() as u64;
+        let mut reader = io::Cursor::new(records.as_bytes());
+
+        assert_eq!(first_record_since(&mut reader, len, 0).unwrap(), 0);
+        assert_eq!(first_record_since(&mut reader, len, 1000).unwrap(), 0);
+        let offset = first_record_since(&mut reader, len, 1205).unwrap();
+        assert_eq!(&records[offset as usize..offset as usize + 4], "1210");
+        assert_eq!(first_record_since(&mut reader, len, 1990).unwrap(), last);
+        assert_eq!(first_record_since(&mut reader, len, 5000).unwrap(), len);
+    }
+
+    #[test]
+    fn skip_old_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.tsv");
+        for i in 0..1000 {
+            append_record(&path, &record(i, "/old", "make")).unwrap();
+        }
+        append_record(&path, &record(100_000, "/new", "make")).unwrap();
+
+        // The old records are not read, so they cannot annotate older entries
+        let mut history = [entry(500, "make"), entry(100_000, "make")];
+        annotate_from(&path, &mut history[1..]).unwrap();
+        assert_eq!(cwd(&history[1]), Some("/new"));
+        annotate_from(&path, &mut history).unwrap();
+        assert_eq!(cwd(&history[0]), Some("/old"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keep_datastore_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.tsv");
+        append_record(&path, &record(1000, "/srv", "ls")).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
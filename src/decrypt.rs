@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::error::{HistoryError, TermsearchError};
+
+/// Time given to the decryption command, e.g. waiting for a passphrase, before it is killed.
+const DECRYPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval between checks of whether the decryption command exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Decrypt a history file with a command reading it from its standard input.
+///
+/// The decrypted contents are only kept in memory, and never logged.
+///
+/// # Arguments
+///
+/// * `path`: The path to the encrypted history file.
+/// * `command`: The shell command writing the decrypted history to its standard output.
+///
+/// # Returns
+///
+/// The decrypted contents of the history file.
+///
+pub fn decrypt(path: &Path, command: &str) -> Result<Vec<u8>> {
+    debug!("Decrypt history file {:?} with: {}", path, command);
+
+    let decrypt_error = |message: String| TermsearchError::Decrypt {
+        path: path.to_path_buf(),
+        message,
+    };
+
+    let file = File::open(path).map_err(HistoryError::opening(path))?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::from(file))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| decrypt_error(format!("failed to run {:?} ({})", command, e)))?;
+
+    // Drain both outputs while waiting, so that the command never blocks on a full pipe
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let stdout = thread::spawn(move || {
+        let mut contents = Vec::new();
+        stdout.read_to_end(&mut contents).map(|_| contents)
+    });
+    let stderr = thread::spawn(move || {
+        let mut contents = Vec::new();
+        stderr.read_to_end(&mut contents).map(|_| contents)
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= DECRYPT_TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Err(decrypt_error(format!(
+                "{:?} timed out after {:?}",
+                command, DECRYPT_TIMEOUT
+            ))
+            .into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    let stderr = stderr.join().unwrap_or_else(|_| Ok(Vec::new()))?;
+    if !status.success() {
+        let mut message = String::from_utf8_lossy(&stderr).trim().to_string();
+        if message.is_empty() {
+            message = format!("{:?} failed with {}", command, status);
+        }
+        return Err(decrypt_error(message).into());
+    }
+
+    let contents = stdout
+        .join()
+        .map_err(|_| decrypt_error("failed to read the decrypted history".to_string()))??;
+    debug!("Decrypted {} bytes of history", contents.len());
+    Ok(contents)
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// Errors raised while reading and editing history files.
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    /// No history file could be found.
+    #[error("history file not found at {0:?}")]
+    NotFound(PathBuf),
+    /// The history file set in `HISTFILE` could not be found.
+    #[error("history file not found at {0:?}, as set in HISTFILE")]
+    HistfileNotFound(PathBuf),
+    /// The history file cannot be read with the current permissions.
+    #[error("permission denied to read history file {0:?}")]
+    PermissionDenied(PathBuf),
+    /// None of the lines of the history file could be parsed.
+    #[error("none of the {lines} lines of history file {path:?} could be parsed")]
+    Parse { path: PathBuf, lines: usize },
+    /// The history file no longer holds the entries read from it.
+    #[error("history file {0:?} changed since it was read")]
+    Changed(PathBuf),
+    /// A history file could not be read or written.
+    #[error("failed to read history file {path:?}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl HistoryError {
+    /// Build a closure wrapping an IO error raised while opening a history file.
+    ///
+    /// Missing files and denied permissions get their own variant, so that
+    /// they can be told apart from other failures.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The path to the history file.
+    ///
+    pub fn opening(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| match source.kind() {
+            io::ErrorKind::NotFound => Self::NotFound(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(path.to_path_buf()),
+            _ => Self::Io {
+                path: path.to_path_buf(),
+                source,
+            },
+        }
+    }
+}
+
+/// Errors raised by termsearch.
+#[derive(Debug, Error)]
+pub enum TermsearchError {
+    /// A history file could not be read or edited.
+    #[error(transparent)]
+    History(#[from] HistoryError),
+    /// The shell is not supported.
+    #[error("unsupported shell {0:?}, use --shell to select a supported one")]
+    UnsupportedShell(String),
+    /// A history database could not be queried.
+    #[error("failed to query history database {path:?}: {message}")]
+    Database { path: PathBuf, message: String },
+    /// A history file could not be decrypted.
+    #[error("failed to decrypt history file {path:?}: {message}")]
+    Decrypt { path: PathBuf, message: String },
+    /// The terminal could not be set up or restored.
+    #[error("failed to {action}")]
+    TerminalSetup {
+        action: &'static str,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl TermsearchError {
+    /// Build a closure wrapping an IO error into a terminal setup error.
+    ///
+    /// # Arguments
+    ///
+    /// * `action`: The terminal action that failed.
+    ///
+    pub fn terminal_setup(action: &'static str) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::TerminalSetup { action, source }
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+/// Maximum number of files listed for a path.
+const MAX_FILES: usize = 50;
+
+/// Maximum depth of the directories searched by `rg`, which has no result limit.
+const MAX_DEPTH: usize = 8;
+
+/// Time after which a file search is stopped, with the files found so far kept.
+const FIND_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Extract the tokens of a command that look like file paths.
+///
+/// A token is considered a path if it is not an option and contains a `/` or
+/// a file extension.
+///
+/// # Arguments
+///
+/// * `command`: The command to extract paths from.
+///
+/// # Returns
+///
+/// The path-like tokens, without surrounding quotes.
+///
+pub fn extract_paths(command: &str) -> Vec<String> {
+    command
+        .split_whitespace()
+        .skip(1)
+        .map(|token| token.trim_matches(|c| c == '"' || c == '\''))
+        .filter(|token| !token.starts_with('-') && !token.contains("://"))
+        .filter(|token| {
+            token.contains('/')
+                || Path::new(token)
+                    .extension()
+                    .is_some_and(|ext| ext.to_string_lossy().chars().all(char::is_alphanumeric))
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+/// Find files matching the name of a path with `fd`, or `rg` if `fd` is missing.
+///
+/// # Arguments
+///
+/// * `path`: The path whose file name is looked for.
+///
+/// # Returns
+///
+/// The matching files, or a short explanation if none could be listed.
+///
+pub fn find_files(path: &str) -> Vec<String> {
+    let name = Path::new(path).file_name().map_or_else(
+        || path.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    debug!("Find files named {}", name);
+
+    let fd = run_tool(
+        "fd",
+        &[
+            "--fixed-strings",
+            "--max-results",
+            &MAX_FILES.to_string(),
+            "--",
+            &name,
+        ],
+    );
+    let files = fd.or_else(|| {
+        run_tool(
+            "rg",
+            &[
+                "--files",
+                "--max-depth",
+                &MAX_DEPTH.to_string(),
+                "--glob",
+                &format!("*{}*", name),
+            ],
+        )
+    });
+
+    match files {
+        Some(files) if files.is_empty() => vec![format!("No files found for {}", name)],
+        Some(files) => files,
+        None => vec!["fd or rg is required to find files".to_string()],
+    }
+}
+
+/// Run an external tool and collect its first output lines.
+///
+/// The tool is killed once `MAX_FILES` lines were read or after `FIND_TIMEOUT`,
+/// so a search from a large directory tree cannot hang the caller.
+///
+/// # Arguments
+///
+/// * `program`: The program to run.
+/// * `args`: The program arguments.
+///
+/// # Returns
+///
+/// The output lines, or `None` if the program could not be run.
+///
+fn run_tool(program: &str, args: &[&str]) -> Option<Vec<String>> {
+    let mut child = match Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            debug!("Failed to run {}: {}", program, e);
+            return None;
+        }
+    };
+
+    // Read the output from another thread so the deadline holds while no line comes
+    let stdout = child.stdout.take()?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let deadline = Instant::now() + FIND_TIMEOUT;
+    let mut lines = Vec::new();
+    while lines.len() < MAX_FILES {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match receiver.recv_timeout(timeout) {
+            Ok(line) => lines.push(line),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                debug!("Stop {} after {:?}", program, FIND_TIMEOUT);
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // The tool may still be running when enough lines were read
+    let _ = child.kill();
+    let _ = child.wait();
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_path_tokens() {
+        assert_eq!(
+            extract_paths("vim -p 'src/main.rs' Cargo.toml https://example.com/a.rs ls"),
+            ["src/main.rs", "Cargo.toml"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_tool_after_max_files() {
+        let lines = run_tool("yes", &["file"]).unwrap();
+        assert_eq!(lines.len(), MAX_FILES);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stop_tool_after_timeout() {
+        let start = Instant::now();
+        let lines = run_tool("sh", &["-c", "echo file; sleep 30"]).unwrap();
+        assert_eq!(lines, ["file"]);
+        assert!(start.elapsed() < FIND_TIMEOUT * 2);
+    }
+
+    #[test]
+    fn skip_missing_tool() {
+        assert!(run_tool("termsearch-missing-tool", &[]).is_none());
+    }
+}
@@ -0,0 +1,14 @@
+pub mod aliases;
+pub mod cache;
+pub mod datastore;
+pub mod decrypt;
+pub mod error;
+pub mod files;
+pub mod history;
+pub mod logger;
+pub mod man;
+pub mod matcher;
+pub mod output;
+pub mod search;
+pub mod sqlite;
+pub mod ui;
@@ -4,15 +4,16 @@ use std::path::PathBuf;
 use std::sync::Mutex;
 
 use chrono::{DateTime, Local};
-use log::{Level, Log, Metadata, Record};
+use log::{LevelFilter, Log, Metadata, Record};
 
 // A simple logger that writes to a file
 pub struct Logger {
     file: Mutex<File>,
+    level: LevelFilter,
 }
 
 impl Logger {
-    pub fn new(log_file_path: PathBuf) -> Result<Self, std::io::Error> {
+    pub fn new(log_file_path: PathBuf, level: LevelFilter) -> Result<Self, std::io::Error> {
         // Create the log file or append to it if it exists
         let log_file = OpenOptions::new()
             .append(true)
@@ -21,6 +22,7 @@ impl Logger {
 
         Ok(Logger {
             file: Mutex::new(log_file),
+            level,
         })
     }
 }
@@ -28,7 +30,7 @@ impl Logger {
 impl Log for Logger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         // Enable all messages at or above the configured level
-        metadata.level() <= Level::Debug
+        metadata.level() <= self.level
     }
 
     fn log(&self, record: &Record) {
@@ -52,3 +54,49 @@ impl Log for Logger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    /// Log a message at each level and read back the levels written.
+    fn logged_levels(level: LevelFilter) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("termsearch.log");
+        let logger = Logger::new(path.clone(), level).unwrap();
+
+        for level in [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .args(format_args!("message"))
+                    .build(),
+            );
+        }
+        logger.flush();
+
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(['[', ']']).nth(1).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn log_at_configured_level() {
+        assert_eq!(logged_levels(LevelFilter::Off), Vec::<String>::new());
+        assert_eq!(logged_levels(LevelFilter::Error), ["ERROR"]);
+        assert_eq!(logged_levels(LevelFilter::Info), ["ERROR", "WARN", "INFO"]);
+        assert_eq!(
+            logged_levels(LevelFilter::Trace),
+            ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
+        );
+    }
+}
@@ -1,20 +1,29 @@
-mod history;
-mod logger;
-mod search;
-mod ui;
-
-use std::fs::File;
-use std::io::Write;
+use std::env;
 use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, TimeDelta, TimeZone, Utc};
+use clap::{ArgGroup, Parser, Subcommand};
+use log::{debug, info, warn, LevelFilter};
+use regex::Regex;
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use log::{debug, LevelFilter};
+use termsearch::aliases::read_aliases;
+use termsearch::datastore::{self, Record};
+use termsearch::error::{HistoryError, TermsearchError};
+use termsearch::history::{
+    convert_history, default_hosts_dir, missing_histfile, prune_history, read_history,
+    shell_history_size, zsh_sessions_dir, HistoryBackend, HistoryFollower, HistoryFormat,
+    HistoryLoadOptions, PruneOptions, Shell, DEFAULT_MAX_HISTORY,
+};
+use termsearch::logger::Logger;
+use termsearch::matcher::zsh_glob_regex;
+use termsearch::output::{write_selection, OutputMode, OutputOptions};
+use termsearch::search::SearchOptions;
+use termsearch::ui::{InitialSelection, TerminalUi, UiOptions};
 
-use crate::history::read_zsh_history;
-use crate::logger::Logger;
-use crate::search::{get_frequent_commands, search_commands};
-use crate::ui::TerminalUi;
+/// Share of malformed history lines above which a warning is printed.
+const MALFORMED_WARNING_RATIO: f32 = 0.2;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -27,10 +36,74 @@ struct Args {
     command: Command,
 }
 
+#[derive(Subcommand, Debug)]
+enum HistoryCommand {
+    /// Record a command with the context it was run in, called by the shell integration.
+    Add {
+        /// The working directory the command was run in.
+        #[arg(long = "cwd")]
+        cwd: Option<String>,
+        /// The terminal session the command was run in.
+        #[arg(long = "session")]
+        session: Option<String>,
+        /// The command, after `--`.
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+}
+
+// The command is parsed once at startup, so the size of its variants does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Command {
     /// Initialize for the current shell.
-    Init,
+    Init {
+        /// The shell to initialize (detected from $SHELL by default).
+        #[arg(long = "shell", value_enum)]
+        shell: Option<Shell>,
+    },
+    /// Manage the commands recorded by termsearch.
+    History {
+        #[command(subcommand)]
+        action: HistoryCommand,
+    },
+    /// Remove entries from the history file of the shell, rewriting it in place.
+    #[command(group = ArgGroup::new("filters").required(true).multiple(true))]
+    Prune {
+        /// The shell whose history file is pruned (detected from $SHELL by default).
+        #[arg(long = "shell", value_enum)]
+        shell: Option<Shell>,
+        /// Remove every occurrence of a command but the most recent one.
+        #[arg(long = "dedup", group = "filters")]
+        dedup: bool,
+        /// Remove commands run before a duration ago (e.g. 12h, 7d, 2w, 1y), a date (YYYY-MM-DD), today or yesterday.
+        #[arg(long = "older-than", value_name = "WHEN", value_parser = parse_time, group = "filters")]
+        older_than: Option<DateTime<Utc>>,
+        /// Only keep the last entries of the history file.
+        #[arg(long = "keep-last", value_name = "N", group = "filters")]
+        keep_last: Option<usize>,
+        /// Print how many entries would be removed without changing the history file.
+        #[arg(long = "dry-run")]
+        dry_run: bool,
+    },
+    /// Convert a history file to the history format of another shell.
+    Convert {
+        /// The shell that wrote the input file.
+        #[arg(long = "from", value_enum)]
+        from: Shell,
+        /// The shell whose history format is written.
+        #[arg(long = "to", value_enum)]
+        to: Shell,
+        /// The input history file [default: the history file of --from].
+        #[arg(long = "input", value_name = "FILE")]
+        input: Option<PathBuf>,
+        /// The output history file, which must not exist unless --append is given.
+        #[arg(long = "output", value_name = "FILE")]
+        output: PathBuf,
+        /// Append the commands missing from the output file, writing each command once.
+        #[arg(long = "append")]
+        append: bool,
+    },
     /// Search through the shell history.
     Search {
         /// The search term (optional).
@@ -38,19 +111,418 @@ enum Command {
         /// The output file (optional).
         #[arg(short = 'o')]
         output_file: Option<String>,
-        /// Maximum number of history lines to read.
-        #[arg(short = 'm', long = "max-history", default_value = "10000")]
-        max_history: usize,
+        /// Where the selected command is written (file requires -o).
+        #[arg(long = "output", value_enum, default_value_t = OutputMode::File)]
+        output: OutputMode,
+        /// Shell-quote the selected command when writing it.
+        #[arg(long = "escape")]
+        escape: bool,
+        /// The shell whose history is searched (detected from $SHELL by default).
+        #[arg(long = "shell", value_enum)]
+        shell: Option<Shell>,
+        /// Where the history is read from.
+        #[arg(long = "source", value_enum, default_value_t = HistoryBackend::File)]
+        source: HistoryBackend,
+        /// Read the history from the standard input, in the format of the shell's history file (same as --source stdin).
+        #[arg(long = "stdin", conflicts_with = "source")]
+        stdin: bool,
+        /// Maximum number of history lines to read [default: $SAVEHIST, $HISTSIZE or 10000].
+        #[arg(
+            short = 'm',
+            long = "max-history",
+            env = "TERMSEARCH_MAX_HISTORY",
+            value_parser = at_least_one
+        )]
+        max_history: Option<usize>,
         /// Maximum number of results to display.
-        #[arg(short = 'r', long = "max-results", default_value = "10")]
+        #[arg(
+            short = 'r',
+            long = "max-results",
+            default_value = "10",
+            value_parser = at_least_one
+        )]
         max_results: usize,
+        /// Also read rotated history archives (e.g. `.zsh_history.1`, `.zsh_history.2.gz`).
+        #[arg(long = "include-rotated")]
+        include_rotated: bool,
+        /// Fail when HISTFILE is set to a missing file instead of reading the default history file.
+        #[arg(long = "strict-histfile")]
+        strict_histfile: bool,
+        /// Decrypt the history file with a shell command reading it from stdin, e.g. `age -d -i ~/.key`.
+        #[arg(
+            long = "decrypt-command",
+            value_name = "COMMAND",
+            env = "TERMSEARCH_DECRYPT_CMD"
+        )]
+        decrypt_command: Option<String>,
+        /// Parse the whole history file instead of using the cache of the parsed history.
+        #[arg(long = "no-cache")]
+        no_cache: bool,
+        /// Also read the per-session histories of macOS Terminal in `~/.zsh_sessions` (default on macOS when it exists).
+        #[arg(long = "include-sessions", overrides_with = "no_include_sessions")]
+        include_sessions: bool,
+        /// Do not read the per-session histories of macOS Terminal.
+        #[arg(long = "no-include-sessions")]
+        no_include_sessions: bool,
+        /// Also read the history files of other hosts, named `<host>.<history file>` (e.g. `server.zsh_history`).
+        #[arg(long = "include-hosts")]
+        include_hosts: bool,
+        /// The directory of the history files of other hosts [default: ~/.termsearch/hosts].
+        #[arg(
+            long = "hosts-dir",
+            value_name = "DIR",
+            env = "TERMSEARCH_HOSTS_DIR",
+            requires = "include_hosts"
+        )]
+        hosts_dir: Option<PathBuf>,
+        /// Only read the last bytes of the history file.
+        #[arg(long = "limit-bytes", value_name = "BYTES")]
+        limit_bytes: Option<u64>,
+        /// Only show commands run since a duration ago (e.g. 30m, 12h, 7d, 2w), a date (YYYY-MM-DD), today or yesterday.
+        #[arg(long = "since", value_name = "WHEN", value_parser = parse_time)]
+        since: Option<DateTime<Utc>>,
+        /// Only show commands run before a duration ago (e.g. 30m, 12h, 7d, 2w), a date (YYYY-MM-DD), today or yesterday.
+        #[arg(long = "until", value_name = "WHEN", value_parser = parse_time)]
+        until: Option<DateTime<Utc>>,
+        /// Only show commands of at least this many characters.
+        #[arg(long = "min-length", value_name = "CHARS")]
+        min_length: Option<usize>,
+        /// Merge repeated commands into a single entry, counting how often each was run.
+        #[arg(long = "dedup")]
+        dedup: bool,
+        /// Show commands starting with a space, which are hidden like shells do with HIST_IGNORE_SPACE.
+        #[arg(long = "no-ignore-space")]
+        no_ignore_space: bool,
+        /// Hide commands matching a ZSH glob pattern, e.g. `(ls|cd ..|*password*)` (defaults to $HISTORY_IGNORE with zsh).
+        #[arg(
+            long = "ignore",
+            value_name = "PATTERN",
+            env = "TERMSEARCH_IGNORE",
+            value_parser = parse_ignore_pattern
+        )]
+        ignore: Option<Regex>,
+        /// Parse history lines with a regex instead of the shell's format, with named groups `cmd` and optionally `ts` and `cwd`.
+        #[arg(
+            long = "history-format",
+            value_name = "REGEX",
+            env = "TERMSEARCH_HISTORY_FORMAT",
+            value_parser = parse_history_format
+        )]
+        history_format: Option<Regex>,
+        /// The strftime format of the `ts` group of --history-format (Unix timestamps or RFC 3339 dates by default).
+        #[arg(
+            long = "timestamp-format",
+            value_name = "FORMAT",
+            env = "TERMSEARCH_TIMESTAMP_FORMAT",
+            requires = "history_format"
+        )]
+        timestamp_format: Option<String>,
+        /// Add commands to the results as they are written to the history file.
+        #[arg(long = "follow")]
+        follow: bool,
+        /// Collapse runs of whitespace when matching commands.
+        #[arg(long = "normalize-whitespace")]
+        normalize_whitespace: bool,
+        /// Collapse runs of whitespace and strip trailing `;` and `&` when matching and counting commands.
+        #[arg(long = "normalize")]
+        normalize: bool,
+        /// Match against the shape of commands, with argument values masked.
+        #[arg(long = "shape")]
+        shape: bool,
+        /// Rank matches with the same case as the search term first, still matching other cases.
+        #[arg(long = "prefer-exact-case")]
+        prefer_exact_case: bool,
+        /// Match case-sensitively even when the search term is all lowercase.
+        #[arg(long = "case-sensitive")]
+        case_sensitive: bool,
+        /// Match the search term as a regular expression (toggle with Ctrl+R).
+        #[arg(long = "regex")]
+        regex: bool,
+        /// Only match the search term as a substring, without matching its characters in order.
+        #[arg(long = "no-fuzzy")]
+        no_fuzzy: bool,
+        /// Do not rank commands run in the current directory or one of its parents higher.
+        #[arg(long = "no-directory-boost")]
+        no_directory_boost: bool,
+        /// Weight given to how recently a command was run [default: 0.6].
+        #[arg(
+            long = "recency-weight",
+            value_name = "WEIGHT",
+            env = "TERMSEARCH_RECENCY_WEIGHT",
+            value_parser = non_negative_weight
+        )]
+        recency_weight: Option<f32>,
+        /// Weight given to how often a command was run [default: 0.4].
+        #[arg(
+            long = "frequency-weight",
+            value_name = "WEIGHT",
+            env = "TERMSEARCH_FREQUENCY_WEIGHT",
+            value_parser = non_negative_weight
+        )]
+        frequency_weight: Option<f32>,
+        /// Weight given to how well a command matches the search term, 0 ignoring it.
+        #[arg(
+            long = "match-weight",
+            value_name = "WEIGHT",
+            env = "TERMSEARCH_MATCH_WEIGHT",
+            value_parser = non_negative_weight,
+            default_value_t = 1.0
+        )]
+        match_weight: f32,
+        /// Number of days after which the recency of a command is halved.
+        #[arg(
+            long = "recency-half-life",
+            value_name = "DAYS",
+            env = "TERMSEARCH_RECENCY_HALF_LIFE",
+            value_parser = positive_days,
+            default_value = "30"
+        )]
+        recency_half_life: Duration,
+        /// Minimum score of the results of a search, fewer results being listed if needed.
+        #[arg(
+            long = "min-score",
+            value_name = "SCORE",
+            env = "TERMSEARCH_MIN_SCORE",
+            value_parser = non_negative_weight,
+            default_value_t = 0.0
+        )]
+        min_score: f32,
+        /// Rank results by match quality only, ignoring recency and frequency.
+        #[arg(long = "match-only")]
+        match_only: bool,
+        /// Count commands differing only by case as the same command.
+        #[arg(long = "fold-case-frequency")]
+        fold_case_frequency: bool,
+        /// Match an abbreviation to a command name, e.g. `k=kubectl` (repeatable).
+        #[arg(long = "abbrev", value_name = "ABBR=COMMAND", value_parser = parse_abbreviation)]
+        abbreviations: Vec<(String, String)>,
+        /// Also match commands as expanded from the aliases listed in a file, in the format of `alias -L`.
+        #[arg(long = "aliases", value_name = "FILE", env = "TERMSEARCH_ALIASES")]
+        aliases: Option<PathBuf>,
+        /// Maximum time in milliseconds spent searching per keystroke.
+        #[arg(long = "time-budget-ms", value_name = "MS")]
+        time_budget_ms: Option<u64>,
+        /// Select results by clicking them with the mouse.
+        #[arg(long = "mouse")]
+        mouse: bool,
+        /// Which result is selected when results are displayed.
+        #[arg(long = "select", value_enum, default_value_t = InitialSelection::First)]
+        select: InitialSelection,
+        /// Adjust the recency/frequency balance live with Alt+'+' and Alt+'-'.
+        #[arg(long = "tune")]
+        tune: bool,
+        /// Show the manual page of the selected command with F1.
+        #[arg(long = "man")]
+        man: bool,
+        /// List files matching the paths of the selected command with F2 (requires fd or rg).
+        #[arg(long = "find-files")]
+        find_files: bool,
+        /// Maximum number of columns used to render a command.
+        #[arg(long = "max-width", value_name = "COLUMNS")]
+        max_width: Option<u16>,
+        /// Show the score of each result, with its match, recency and frequency parts.
+        #[arg(long = "show-scores")]
+        show_scores: bool,
     },
 }
 
+/// Parse a count argument that must be at least 1.
+fn at_least_one(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a scoring weight, which must be a non-negative number.
+fn non_negative_weight(value: &str) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(weight) if weight.is_finite() && weight >= 0.0 => Ok(weight),
+        Ok(_) => Err("must be a non-negative number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a number of days, which must be positive, into a duration.
+fn positive_days(value: &str) -> Result<Duration, String> {
+    match value.parse::<f64>() {
+        Ok(days) if days > 0.0 => {
+            Duration::try_from_secs_f64(days * 86400.0).map_err(|e| e.to_string())
+        }
+        Ok(_) => Err("must be a positive number".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a point in time given as a duration ago (e.g. `7d`), a date (`YYYY-MM-DD`),
+/// `today` or `yesterday`, dates standing for their local midnight.
+fn parse_time(value: &str) -> Result<DateTime<Utc>, String> {
+    let today = Local::now().date_naive();
+    let date = match value {
+        "today" => Some(today),
+        "yesterday" => today.pred_opt(),
+        _ => NaiveDate::parse_from_str(value, "%Y-%m-%d").ok(),
+    };
+    if let Some(date) = date {
+        return date
+            .and_hms_opt(0, 0, 0)
+            .and_then(|datetime| Local.from_local_datetime(&datetime).single())
+            .map(|datetime| datetime.with_timezone(&Utc))
+            .ok_or_else(|| "invalid date".to_string());
+    }
+
+    let expected =
+        || "expected a duration (e.g. 7d), a date (YYYY-MM-DD), today or yesterday".to_string();
+    let (split, unit) = value.char_indices().next_back().ok_or_else(expected)?;
+    let amount: i64 = value[..split].parse().map_err(|_| expected())?;
+    let duration = match unit {
+        'm' => TimeDelta::try_minutes(amount),
+        'h' => TimeDelta::try_hours(amount),
+        'd' => TimeDelta::try_days(amount),
+        'w' => TimeDelta::try_weeks(amount),
+        'y' => amount.checked_mul(365).and_then(TimeDelta::try_days),
+        _ => return Err("expected a unit among m, h, d, w and y".to_string()),
+    };
+
+    duration
+        .and_then(|duration| Utc::now().checked_sub_signed(duration))
+        .ok_or_else(|| "duration out of range".to_string())
+}
+
+/// Parse a history format argument, a regex with a named group `cmd`.
+fn parse_history_format(value: &str) -> Result<Regex, String> {
+    let pattern = Regex::new(value).map_err(|e| e.to_string())?;
+    if !pattern.capture_names().any(|name| name == Some("cmd")) {
+        return Err("expected a named group `cmd`, e.g. (?<cmd>.*)".to_string());
+    }
+    Ok(pattern)
+}
+
+/// Parse an ignore pattern argument, a ZSH glob pattern.
+fn parse_ignore_pattern(value: &str) -> Result<Regex, String> {
+    zsh_glob_regex(value).map_err(|e| e.to_string())
+}
+
+/// Parse an abbreviation argument of the form `ABBR=COMMAND`.
+fn parse_abbreviation(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((abbr, command)) if !abbr.is_empty() && !command.is_empty() => {
+            Ok((abbr.to_string(), command.to_string()))
+        }
+        _ => Err("expected ABBR=COMMAND".to_string()),
+    }
+}
+
+/// Get the history error behind an error, raised as is or wrapped in a `TermsearchError`.
+///
+/// # Arguments
+///
+/// * `error`: The error raised.
+///
+/// # Returns
+///
+/// The history error, if the error is one.
+///
+fn history_error(error: &anyhow::Error) -> Option<&HistoryError> {
+    match error.downcast_ref::<TermsearchError>() {
+        Some(TermsearchError::History(error)) => Some(error),
+        _ => error.downcast_ref::<HistoryError>(),
+    }
+}
+
+/// Get guidance on how to fix an error raised while reading the history.
+///
+/// # Arguments
+///
+/// * `error`: The error raised.
+///
+/// # Returns
+///
+/// A hint for the user, if there is one for this error.
+///
+fn read_hint(error: &HistoryError) -> Option<&'static str> {
+    match error {
+        HistoryError::NotFound(_) => Some(
+            "set HISTFILE to the history file of your shell, or select another shell with --shell",
+        ),
+        HistoryError::HistfileNotFound(_) => Some(
+            "fix HISTFILE in your shell configuration, or drop --strict-histfile to read the default history file",
+        ),
+        HistoryError::PermissionDenied(_) => {
+            Some("make the history file readable by your user")
+        }
+        HistoryError::Parse { .. } => Some(
+            "select the shell that wrote the history with --shell, or describe its format with --history-format",
+        ),
+        _ => None,
+    }
+}
+
 /// Initialize termsearch for the current shell.
-pub fn handle_init() -> Result<()> {
-    let zsh_script = include_str!("../termsearch.zsh");
-    println!("{}", zsh_script);
+///
+/// # Arguments
+///
+/// * `shell`: The shell to initialize.
+///
+pub fn handle_init(shell: Shell) -> Result<()> {
+    let script = match shell {
+        Shell::Zsh => include_str!("../termsearch.zsh"),
+        Shell::Fish => include_str!("../termsearch.fish"),
+        Shell::Bash => return Err(TermsearchError::UnsupportedShell("bash".to_string()).into()),
+        Shell::Nu => return Err(TermsearchError::UnsupportedShell("nu".to_string()).into()),
+        Shell::Powershell => {
+            return Err(TermsearchError::UnsupportedShell("powershell".to_string()).into())
+        }
+    };
+    println!("{}", script);
+
+    Ok(())
+}
+
+/// Handle the prune command.
+///
+/// # Arguments
+///
+/// * `shell`: The shell whose history file is pruned.
+/// * `options`: Which entries are removed.
+/// * `dry_run`: Only print how many entries would be removed.
+///
+pub fn handle_prune(shell: Shell, options: PruneOptions, dry_run: bool) -> Result<()> {
+    let (removed, total) = prune_history(shell, &options, dry_run)?;
+    if dry_run {
+        println!("Would remove {} of {} history entries", removed, total);
+    } else {
+        println!("Removed {} of {} history entries", removed, total);
+    }
+
+    Ok(())
+}
+
+/// Handle the convert command.
+///
+/// # Arguments
+///
+/// * `from`: The shell that wrote the input file.
+/// * `input`: The input history file, or the history file of `from` if `None`.
+/// * `to`: The shell whose history format is written.
+/// * `output`: The output history file.
+/// * `append`: Merge the converted commands into an existing output file.
+///
+pub fn handle_convert(
+    from: Shell,
+    input: Option<PathBuf>,
+    to: Shell,
+    output: PathBuf,
+    append: bool,
+) -> Result<()> {
+    let (written, total) = convert_history(from, input.as_deref(), to, &output, append)?;
+    println!(
+        "Wrote {} of {} history entries to {}",
+        written,
+        total,
+        output.display()
+    );
 
     Ok(())
 }
@@ -60,40 +532,93 @@ pub fn handle_init() -> Result<()> {
 /// # Arguments
 ///
 /// * `term`: The search term (optional).
-/// * `max_history`: Maximum number of history entries to read.
+/// * `history_options`: Options controlling which history entries are loaded.
+/// * `follow`: Add commands to the results as they are written to the history file.
 /// * `max_results`: Maximum number of results to display.
-/// * `output_file`: File to write the selected command (optional).
+/// * `output_options`: Options controlling how the selected command is written.
+/// * `search_options`: Options used to match and rank commands.
+/// * `ui_options`: Options controlling the UI behavior.
 ///
 pub fn handle_search(
     term: Option<String>,
-    max_history: usize,
+    history_options: HistoryLoadOptions,
+    follow: bool,
     max_results: usize,
-    output_file: Option<String>,
+    output_options: OutputOptions,
+    search_options: SearchOptions,
+    ui_options: UiOptions,
 ) -> Result<()> {
-    // Read ZSH history
-    let history = read_zsh_history(max_history)?;
+    // Read shell history, with guidance on how to fix the most common failures
+    let (history, stats) = read_history(&history_options).map_err(|e| {
+        match history_error(&e).and_then(read_hint) {
+            Some(hint) => anyhow!("{:#}\nhint: {}", e, hint),
+            None => e,
+        }
+    })?;
+
+    // Make lines lost to an unexpected format visible before the UI takes over the screen
+    info!("History file {}", stats);
+    if stats.malformed_ratio() > MALFORMED_WARNING_RATIO {
+        let hint = match history_options.shell {
+            Shell::Zsh => ", it may not be in the extended format (setopt EXTENDED_HISTORY)",
+            _ => "",
+        };
+        eprintln!(
+            "warning: skipped {} of {} history lines in an unexpected format{}",
+            stats.malformed, stats.lines, hint
+        );
+    }
 
     // Initialize UI
-    let mut ui = TerminalUi::new(max_results, history)?;
+    let mut ui = TerminalUi::new(max_results, history, search_options.clone(), ui_options)?;
+    ui.set_filters(history_options.filter_labels());
+    if history_options.reads_history_file() {
+        // A mistyped HISTFILE would otherwise go unnoticed
+        if let Some(histfile) = missing_histfile(history_options.shell) {
+            ui.set_message(format!(
+                "HISTFILE {:?} not found, read the default",
+                histfile
+            ));
+        }
+    }
+    if history_options.backend != HistoryBackend::Stdin {
+        // Standard input was consumed and cannot be read again
+        ui.allow_reload(history_options.clone());
+    }
+    if history_options.reads_history_file() && !history_options.dedup {
+        ui.allow_deletion(history_options.shell);
+    }
+    if follow && history_options.reads_history_file() {
+        let max_history = history_options.max_history;
+        ui.follow(
+            HistoryFollower::new(
+                history_options.shell,
+                max_history,
+                history_options.format.clone(),
+            )?,
+            max_history,
+        );
+    }
 
     // Perform search (display most frequent commands if no term provided)
     let initial_matches = if let Some(term) = &term {
-        search_commands(term, &ui.history, max_results)
+        ui.index.search(term, max_results, &search_options)
     } else {
-        get_frequent_commands(&ui.history, max_results)
+        ui.index.frequent(max_results, &search_options)
     };
 
     // Display initial results
     ui.set_initial_results(initial_matches)?;
 
-    // Run the UI and get the selected command
-    if let Some(selected_command) = ui.run(term)? {
-        debug!("Selected command: {}", selected_command);
-        if let Some(output_file) = output_file {
-            debug!("Write command to output file: {}", output_file);
-            let mut file = File::create(&output_file)?;
-            writeln!(file, "commandline\t{}", selected_command)?;
+    // Run the UI and get the selected command, restoring the terminal before writing it
+    let selected_command = ui.run(term)?;
+    drop(ui);
+    if let Some(selected_command) = selected_command {
+        // Commands of an encrypted history are kept out of the log
+        if history_options.decrypt_command.is_none() {
+            debug!("Selected command: {}", selected_command);
         }
+        write_selection(&selected_command, &output_options)?;
     }
 
     Ok(())
@@ -118,7 +643,7 @@ fn main() -> Result<()> {
         .unwrap_or(LevelFilter::Info);
 
     // Initialize the logger with the specified file path
-    let logger = Logger::new(log_file_path)?;
+    let logger = Logger::new(log_file_path, file_log_level)?;
     log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(file_log_level))?;
 
     // Get the version from Cargo at compile time
@@ -128,16 +653,320 @@ fn main() -> Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Command::Init => handle_init()?,
+        Command::Init { shell } => handle_init(match shell {
+            Some(shell) => shell,
+            None => Shell::detect()?,
+        })?,
+        Command::History { action } => match action {
+            HistoryCommand::Add {
+                cwd,
+                session,
+                command,
+            } => datastore::add_record(&Record::new(command.join(" "), cwd, session))?,
+        },
+        Command::Prune {
+            shell,
+            dedup,
+            older_than,
+            keep_last,
+            dry_run,
+        } => handle_prune(
+            match shell {
+                Some(shell) => shell,
+                None => Shell::detect()?,
+            },
+            PruneOptions {
+                dedup,
+                older_than,
+                keep_last,
+            },
+            dry_run,
+        )?,
+        Command::Convert {
+            from,
+            to,
+            input,
+            output,
+            append,
+        } => handle_convert(from, input, to, output, append)?,
         Command::Search {
             term,
             output_file,
+            output,
+            escape,
+            shell,
+            source,
+            stdin,
             max_history,
             max_results,
+            include_rotated,
+            strict_histfile,
+            decrypt_command,
+            no_cache,
+            include_sessions,
+            no_include_sessions,
+            include_hosts,
+            hosts_dir,
+            limit_bytes,
+            since,
+            until,
+            min_length,
+            dedup,
+            no_ignore_space,
+            ignore,
+            history_format,
+            timestamp_format,
+            follow,
+            normalize_whitespace,
+            normalize,
+            shape,
+            prefer_exact_case,
+            case_sensitive,
+            regex,
+            no_fuzzy,
+            no_directory_boost,
+            recency_weight,
+            frequency_weight,
+            match_weight,
+            recency_half_life,
+            min_score,
+            match_only,
+            fold_case_frequency,
+            abbreviations,
+            aliases,
+            time_budget_ms,
+            mouse,
+            select,
+            tune,
+            man,
+            find_files,
+            max_width,
+            show_scores,
         } => {
-            handle_search(term, max_history, max_results, output_file)?;
+            let mut search_options = SearchOptions {
+                normalize_whitespace,
+                normalize,
+                shape,
+                prefer_exact_case,
+                case_sensitive,
+                regex,
+                fuzzy: !no_fuzzy,
+                abbreviations: abbreviations.into_iter().collect(),
+                aliases: aliases
+                    .as_deref()
+                    .map(read_aliases)
+                    .transpose()?
+                    .unwrap_or_default(),
+                match_weight,
+                recency_half_life,
+                min_score,
+                fold_case_frequency,
+                time_budget: time_budget_ms.map(Duration::from_millis),
+                directory: match no_directory_boost {
+                    true => None,
+                    false => env::current_dir().ok(),
+                },
+                ..SearchOptions::default()
+            };
+            // A single weight leaves the rest of the balance to the other one
+            match (recency_weight, frequency_weight) {
+                (Some(recency), Some(frequency)) => {
+                    search_options.recency_weight = recency;
+                    search_options.frequency_weight = frequency;
+                }
+                (Some(recency), None) => {
+                    search_options.recency_weight = recency;
+                    search_options.frequency_weight = (1.0 - recency).max(0.0);
+                }
+                (None, Some(frequency)) => {
+                    search_options.recency_weight = (1.0 - frequency).max(0.0);
+                    search_options.frequency_weight = frequency;
+                }
+                (None, None) => {}
+            }
+            if match_only {
+                search_options.recency_weight = 0.0;
+                search_options.frequency_weight = 0.0;
+            }
+            let ui_options = UiOptions {
+                mouse,
+                initial_selection: select,
+                tune,
+                man,
+                find_files,
+                max_width,
+                show_scores,
+            };
+            let shell = match shell {
+                Some(shell) => shell,
+                None => Shell::detect()?,
+            };
+            // Follow the HISTORY_IGNORE parameter of zsh, when it is exported
+            let ignore = ignore.or_else(|| match std::env::var("HISTORY_IGNORE") {
+                Ok(pattern) if shell == Shell::Zsh => zsh_glob_regex(&pattern)
+                    .inspect_err(|e| warn!("Invalid HISTORY_IGNORE {:?}: {}", pattern, e))
+                    .ok(),
+                _ => None,
+            });
+            // The history size of the shell applies unless one is configured
+            let max_history_explicit = max_history.is_some();
+            let (max_history, origin) = match max_history {
+                Some(max_history) => (max_history, "--max-history or TERMSEARCH_MAX_HISTORY"),
+                None => shell_history_size(shell).unwrap_or((DEFAULT_MAX_HISTORY, "default")),
+            };
+            debug!("Read at most {} history lines ({})", max_history, origin);
+            let history_options = HistoryLoadOptions {
+                shell,
+                backend: if stdin { HistoryBackend::Stdin } else { source },
+                max_history,
+                max_history_explicit,
+                include_rotated,
+                include_sessions: include_sessions
+                    || (cfg!(target_os = "macos")
+                        && !no_include_sessions
+                        && zsh_sessions_dir().is_ok_and(|dir| dir.is_dir())),
+                hosts_dir: match (include_hosts, hosts_dir) {
+                    (true, Some(dir)) => Some(dir),
+                    (true, None) => Some(default_hosts_dir()?),
+                    (false, _) => None,
+                },
+                limit_bytes,
+                since,
+                until,
+                min_length,
+                dedup,
+                ignore_space: !no_ignore_space,
+                cache: !no_cache,
+                strict_histfile,
+                decrypt_command,
+                format: history_format.map(|pattern| HistoryFormat::new(pattern, timestamp_format)),
+                ignore,
+            };
+            let output_options = OutputOptions {
+                file: output_file,
+                escape,
+                mode: output,
+            };
+            handle_search(
+                term,
+                history_options,
+                follow,
+                max_results,
+                output_options,
+                search_options,
+                ui_options,
+            )?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parse the arguments of the search command.
+    fn parse_search(args: &[&str]) -> Result<(usize, Option<usize>), clap::Error> {
+        let args = Args::try_parse_from(["termsearch", "search"].iter().chain(args))?;
+        match args.command {
+            Command::Search {
+                max_results,
+                max_history,
+                ..
+            } => Ok((max_results, max_history)),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn reject_zero_counts() {
+        for flag in ["--max-results", "--max-history"] {
+            let error = parse_search(&[flag, "0"]).unwrap_err();
+            assert_eq!(error.kind(), clap::error::ErrorKind::ValueValidation);
+            assert!(error.to_string().contains("must be at least 1"));
+        }
+    }
+
+    #[test]
+    fn accept_counts_of_one() {
+        assert_eq!(parse_search(&["-r", "1"]).unwrap().0, 1);
+        assert_eq!(parse_search(&["-m", "1"]).unwrap().1, Some(1));
+        assert_eq!(
+            at_least_one("-1").unwrap_err(),
+            "invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn validate_weights() {
+        assert_eq!(non_negative_weight("0"), Ok(0.0));
+        assert_eq!(non_negative_weight("1.5"), Ok(1.5));
+        for value in ["-0.1", "inf", "NaN"] {
+            assert!(non_negative_weight(value).is_err(), "{}", value);
+        }
+        assert!(parse_search(&["--recency-weight", "-1"]).is_err());
+    }
+
+    #[test]
+    fn parse_durations_and_dates() {
+        let now = Utc::now();
+        for (value, minutes) in [
+            ("30m", 30),
+            ("2h", 120),
+            ("7d", 7 * 24 * 60),
+            ("1w", 7 * 24 * 60),
+            ("1y", 365 * 24 * 60),
+        ] {
+            let elapsed = now - parse_time(value).unwrap();
+            assert!(
+                (elapsed - TimeDelta::minutes(minutes)).num_seconds().abs() < 60,
+                "{}",
+                value
+            );
+        }
+        let date = parse_time("2024-01-31").unwrap().with_timezone(&Local);
+        assert_eq!(
+            date.date_naive(),
+            NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+        );
+        assert!(parse_time("yesterday").unwrap() < parse_time("today").unwrap());
+    }
+
+    #[test]
+    fn reject_invalid_times() {
+        for value in ["", "d", "7", "x7d", "7x", "7д", "é", "1.5h"] {
+            assert!(parse_time(value).is_err(), "{}", value);
+        }
+        assert_eq!(
+            parse_time("7д").unwrap_err(),
+            "expected a unit among m, h, d, w and y"
+        );
+        assert_eq!(
+            parse_time(&format!("{}y", i64::MAX)).unwrap_err(),
+            "duration out of range"
+        );
+        assert!(parse_time("99999999999w").is_err());
+        assert!(parse_search(&["--since", "7д"]).is_err());
+    }
+
+    #[test]
+    fn hint_history_errors() {
+        let path = PathBuf::from("/nonexistent/.zsh_history");
+        let raised = anyhow::Error::from(HistoryError::NotFound(path.clone()));
+        let wrapped = anyhow::Error::from(TermsearchError::from(HistoryError::NotFound(path)));
+        for error in [&raised, &wrapped] {
+            let hint = history_error(error).and_then(read_hint).unwrap();
+            assert!(hint.contains("HISTFILE"));
+        }
+        assert_eq!(
+            wrapped.to_string(),
+            "history file not found at \"/nonexistent/.zsh_history\""
+        );
+
+        let error = anyhow::Error::from(TermsearchError::UnsupportedShell("tcsh".to_string()));
+        assert!(history_error(&error).is_none());
+        let error = anyhow::Error::from(HistoryError::Changed(PathBuf::new()));
+        assert!(history_error(&error).and_then(read_hint).is_none());
+    }
+}
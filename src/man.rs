@@ -0,0 +1,65 @@
+use std::process::{Command, Stdio};
+
+use log::debug;
+
+/// Read the manual page of a command.
+///
+/// Only `man` is queried: running `<command> --help` would execute arbitrary
+/// programs from the history.
+///
+/// # Arguments
+///
+/// * `name`: The name of the command.
+/// * `width`: The width to format the manual page to.
+///
+/// # Returns
+///
+/// The lines of the manual page, or a short explanation if it is unavailable.
+///
+pub fn read_man_page(name: &str, width: u16) -> Vec<String> {
+    debug!("Read man page for {}", name);
+
+    let output = Command::new("man")
+        .args(["-P", "cat", "--", name])
+        .env("MANWIDTH", width.to_string())
+        .env("GROFF_NO_SGR", "1")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+
+    match output {
+        Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(strip_overstrike)
+                .collect()
+        }
+        Ok(_) => vec![format!("No manual entry for {}", name)],
+        Err(e) => {
+            debug!("Failed to run man: {}", e);
+            vec!["man is not available".to_string()]
+        }
+    }
+}
+
+/// Remove the backspace overstrike sequences used for bold and underlined text.
+///
+/// # Arguments
+///
+/// * `line`: The line to clean.
+///
+/// # Returns
+///
+/// The line without formatting.
+///
+fn strip_overstrike(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    for c in line.chars() {
+        if c == '\u{8}' {
+            text.pop();
+        } else {
+            text.push(c);
+        }
+    }
+    text
+}
@@ -0,0 +1,981 @@
+use std::ops::Range;
+
+use log::debug;
+use regex::{Regex, RegexBuilder};
+
+/// Highest score of a fuzzy match, below the score of any substring match.
+const FUZZY_MAX_SCORE: f32 = 0.5;
+/// Number of characters before a substring match halving its position score.
+const POSITION_HALVING_CHARS: f32 = 16.0;
+/// Length in bytes of commands whose substring matches get half the length penalty.
+const LONG_COMMAND_LEN: f32 = 500.0;
+/// Highest penalty of a substring match in a long command, unless at its start.
+const LONG_COMMAND_PENALTY: f32 = 0.05;
+
+/// A match of a search term in a text.
+#[derive(Debug, Clone)]
+pub struct TermMatch {
+    /// The match score, between 0 (excluded) and 1 (match at the start).
+    pub score: f32,
+    /// The byte ranges of the matched text.
+    pub ranges: Vec<Range<usize>>,
+}
+
+/// How the case of a search term is matched.
+///
+/// Smart-case is the default. Preferring the case of the term only when ranking
+/// matches turns it off, so that other cases are still matched, while forcing
+/// case-sensitive matching takes precedence over both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseMatching {
+    /// Match case-insensitively, unless the term has an uppercase character.
+    Smart,
+    /// Always match case-sensitively.
+    Sensitive,
+    /// Always match case-insensitively.
+    Insensitive,
+}
+
+/// A word of a search term.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryToken {
+    /// The text of the word, without its quotes.
+    pub text: String,
+    /// Whether the word was quoted, and must appear verbatim.
+    pub exact: bool,
+    /// Whether the word started with `^`, and must start the command.
+    pub prefix: bool,
+    /// Whether the word ended with `$`, and must end the command.
+    pub suffix: bool,
+}
+
+impl QueryToken {
+    /// Check whether the word matches like any other word, without quotes or anchors.
+    fn is_plain(&self) -> bool {
+        !self.exact && !self.prefix && !self.suffix
+    }
+}
+
+/// How a search term is matched against commands.
+#[derive(Debug, Clone)]
+pub enum TermMatcher {
+    /// Match the term as a substring, or else as a subsequence of characters if fuzzy.
+    Fuzzy {
+        term: String,
+        tokens: Vec<QueryToken>,
+        case_sensitive: bool,
+        fuzzy: bool,
+    },
+    /// Match the term as a regular expression.
+    Regex(Regex),
+}
+
+impl TermMatcher {
+    /// Create the matcher of a search term.
+    ///
+    /// # Arguments
+    ///
+    /// * `term`: The search term.
+    /// * `regex`: Whether the term is a regular expression.
+    /// * `case`: How the case of the term is matched.
+    /// * `fuzzy`: Whether the term may match as a subsequence when it is not a substring.
+    ///
+    /// # Returns
+    ///
+    /// The matcher, or `None` if the term is not a valid regular expression.
+    ///
+    pub fn new(term: &str, regex: bool, case: CaseMatching, fuzzy: bool) -> Option<Self> {
+        let case_sensitive = is_case_sensitive(term, case);
+
+        if !regex {
+            return Some(Self::Fuzzy {
+                term: term.to_string(),
+                tokens: parse_query(term),
+                case_sensitive,
+                fuzzy,
+            });
+        }
+
+        match RegexBuilder::new(term)
+            .case_insensitive(!case_sensitive)
+            .build()
+        {
+            Ok(regex) => Some(Self::Regex(regex)),
+            Err(e) => {
+                debug!("Invalid regex {:?}: {}", term, e);
+                None
+            }
+        }
+    }
+
+    /// Match the term against a text.
+    ///
+    /// # Arguments
+    ///
+    /// * `text`: The text to search in.
+    ///
+    /// # Returns
+    ///
+    /// The match, or `None` if the text does not match.
+    ///
+    pub fn matches(&self, text: &str) -> Option<TermMatch> {
+        match self {
+            Self::Fuzzy {
+                term,
+                tokens,
+                case_sensitive,
+                fuzzy,
+            } => match_term(text, term, tokens, *case_sensitive, *fuzzy),
+            Self::Regex(regex) => {
+                let start = regex.find(text)?.start();
+                Some(TermMatch {
+                    score: position_score(text, start),
+                    ranges: regex
+                        .find_iter(text)
+                        .map(|found| found.range())
+                        .filter(|range| !range.is_empty())
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    /// Check whether a text contains the term, without fuzzy matching.
+    ///
+    /// # Arguments
+    ///
+    /// * `text`: The text to search in.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the text contains the term or each of its words, or matches the
+    /// regular expression.
+    ///
+    pub fn contained_in(&self, text: &str) -> bool {
+        match self {
+            Self::Fuzzy {
+                term,
+                tokens,
+                case_sensitive,
+                ..
+            } => {
+                let plain = tokens.iter().all(QueryToken::is_plain);
+                term.is_empty()
+                    || (plain && find_term(text, term, *case_sensitive).is_some())
+                    || ((!plain || tokens.len() > 1)
+                        && tokens
+                            .iter()
+                            .all(|token| find_token(text, token, *case_sensitive).is_some()))
+            }
+            Self::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// Split a search term into words.
+///
+/// A word starting with a single quote is matched verbatim, up to the next
+/// single quote ending a word or else to the end of the term, so that both
+/// `'git push' prod` and `'git push` hold the phrase `git push`. A word
+/// starting with `^` must start the command and a word ending with `$` must
+/// end it, with the quotes of a phrase inside these anchors. Other quotes,
+/// carets and dollars, and lone ones, are kept as literal characters.
+///
+/// # Arguments
+///
+/// * `term`: The search term.
+///
+/// # Returns
+///
+/// The words of the term, without empty ones.
+///
+pub fn parse_query(term: &str) -> Vec<QueryToken> {
+    let mut tokens = Vec::new();
+    let mut rest = term.trim_start();
+
+    while !rest.is_empty() {
+        let (prefix, word) = match rest.strip_prefix('^') {
+            Some(word) if word.starts_with(|c: char| !c.is_whitespace()) => (true, word),
+            _ => (false, rest),
+        };
+        let quoted = word
+            .strip_prefix('\'')
+            .filter(|quoted| quoted.starts_with(|c: char| !c.is_whitespace()));
+        let (text, exact, remaining) = match quoted {
+            Some(quoted) => {
+                // The phrase ends at a quote followed by whitespace, an anchor
+                // or the end of the term
+                let end = quoted.match_indices('\'').map(|(i, _)| i).find(|&i| {
+                    let after = &quoted[i + 1..];
+                    after
+                        .strip_prefix('$')
+                        .unwrap_or(after)
+                        .chars()
+                        .next()
+                        .is_none_or(char::is_whitespace)
+                });
+                match end {
+                    Some(end) => (&quoted[..end], true, &quoted[end + 1..]),
+                    None => (quoted.trim_end(), true, ""),
+                }
+            }
+            None => {
+                let end = word.find(char::is_whitespace).unwrap_or(word.len());
+                (&word[..end], false, &word[end..])
+            }
+        };
+        let (text, suffix, remaining) = match remaining.strip_prefix('$') {
+            Some(remaining) if exact => (text, true, remaining),
+            _ => match text.strip_suffix('$') {
+                Some(stripped) if !stripped.is_empty() => (stripped, true, remaining),
+                _ => (text, false, remaining),
+            },
+        };
+
+        if !text.is_empty() {
+            tokens.push(QueryToken {
+                text: text.to_string(),
+                exact,
+                prefix,
+                suffix,
+            });
+        }
+        rest = remaining.trim_start();
+    }
+
+    tokens
+}
+
+/// Match a term against a text.
+///
+/// A term of several words that is not a substring matches when each word
+/// matches on its own, in any order, scored by the average score of the
+/// words. Quoted words only match as substrings and anchored words only at
+/// the start or end, and the term is never matched as a whole when it has either.
+///
+/// A substring match scores highest, above all fuzzy matches, and more so the
+/// closer it is to the start and when it starts a word, especially the first
+/// word. Otherwise, if fuzzy, the term is matched as an
+/// ordered subsequence of characters, scored by how tightly packed the matched
+/// characters are, how many follow each other, how many land on word
+/// boundaries, and whether the first one starts the text.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `tokens`: The words of the search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+/// * `fuzzy`: Whether the term may match as a subsequence.
+///
+/// # Returns
+///
+/// The match, or `None` if the term does not match the text.
+///
+fn match_term(
+    text: &str,
+    term: &str,
+    tokens: &[QueryToken],
+    case_sensitive: bool,
+    fuzzy: bool,
+) -> Option<TermMatch> {
+    if term.is_empty() {
+        return Some(TermMatch {
+            score: 1.0,
+            ranges: Vec::new(),
+        });
+    }
+
+    let plain = tokens.iter().all(QueryToken::is_plain);
+    if tokens.len() > 1 || !plain {
+        // Fast path for a whole term of plain words found as a substring
+        if let (true, Some(range)) = (plain, find_word(text, term, case_sensitive)) {
+            return Some(TermMatch {
+                score: position_score(text, range.start),
+                ranges: vec![range],
+            });
+        }
+        return match_tokens(text, tokens, case_sensitive, fuzzy);
+    }
+
+    match_word(text, term, case_sensitive, fuzzy)
+}
+
+/// Match a word against a text, as a substring or else as a subsequence if fuzzy.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `word`: The word to match.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+/// * `fuzzy`: Whether the word may match as a subsequence.
+///
+/// # Returns
+///
+/// The match, or `None` if the word does not match the text.
+///
+fn match_word(text: &str, word: &str, case_sensitive: bool, fuzzy: bool) -> Option<TermMatch> {
+    // Fast path for substring matches
+    if let Some(range) = find_word(text, word, case_sensitive) {
+        return Some(TermMatch {
+            score: position_score(text, range.start),
+            ranges: vec![range],
+        });
+    }
+
+    if !fuzzy {
+        return None;
+    }
+    fuzzy_match(text, word, case_sensitive)
+}
+
+/// Match each word of a term against a text, in any order.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `tokens`: The words of the search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+/// * `fuzzy`: Whether unquoted words may match as subsequences.
+///
+/// # Returns
+///
+/// The match of every word, or `None` if any word does not match the text.
+///
+fn match_tokens(
+    text: &str,
+    tokens: &[QueryToken],
+    case_sensitive: bool,
+    fuzzy: bool,
+) -> Option<TermMatch> {
+    let mut score = 0.0;
+    let mut ranges = Vec::new();
+    for token in tokens {
+        let token_match = match token.is_plain() {
+            true => match_word(text, &token.text, case_sensitive, fuzzy)?,
+            false => {
+                let range = find_token(text, token, case_sensitive)?;
+                TermMatch {
+                    score: position_score(text, range.start),
+                    ranges: vec![range],
+                }
+            }
+        };
+        score += token_match.score;
+        ranges.extend(token_match.ranges);
+    }
+
+    // Words may match overlapping parts of the text
+    ranges.sort_by_key(|range| range.start);
+    Some(TermMatch {
+        score: score / tokens.len() as f32,
+        ranges: merge_ranges(ranges),
+    })
+}
+
+/// Score an exact match by its position, with a bonus when it starts a word
+/// and another when it is in the first word (the program run), so that a
+/// match at the start scores 1.
+///
+/// The score decreases with the number of characters before the match rather
+/// than its position relative to the length of the text, so that long commands
+/// do not score higher for the same match. Matches not at the start are also
+/// slightly penalized in long commands, keeping matches at the start on top.
+///
+/// # Arguments
+///
+/// * `text`: The matched text.
+/// * `start`: The byte position of the match in the text.
+///
+/// # Returns
+///
+/// The match score, between 0.5 (excluded) and 1.
+///
+fn position_score(text: &str, start: usize) -> f32 {
+    let before = &text[..start];
+    let position = 0.25 / (1.0 + before.chars().count() as f32 / POSITION_HALVING_CHARS);
+    let boundary = if is_word_boundary(text, start) {
+        0.1
+    } else {
+        0.0
+    };
+    let first_word = if before.contains(char::is_whitespace) {
+        0.0
+    } else {
+        0.1
+    };
+    let length_penalty = if start == 0 {
+        0.0
+    } else {
+        LONG_COMMAND_PENALTY * text.len() as f32 / (text.len() as f32 + LONG_COMMAND_LEN)
+    };
+    0.55 + position + boundary + first_word - length_penalty
+}
+
+/// Check whether a term is matched case-sensitively.
+///
+/// # Arguments
+///
+/// * `term`: The search term.
+/// * `case`: How the case of the term is matched.
+///
+/// # Returns
+///
+/// `true` if matching is case-sensitive, or smart-case and the term has an uppercase character.
+///
+fn is_case_sensitive(term: &str, case: CaseMatching) -> bool {
+    match case {
+        CaseMatching::Smart => term.chars().any(char::is_uppercase),
+        CaseMatching::Sensitive => true,
+        CaseMatching::Insensitive => false,
+    }
+}
+
+/// Find the first occurrence of a term in a text.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The byte range of the occurrence in the text, if any.
+///
+fn find_term(text: &str, term: &str, case_sensitive: bool) -> Option<Range<usize>> {
+    // Compare bytes when both are ASCII, where case folding keeps byte offsets
+    if !term.is_empty() && term.is_ascii() && text.is_ascii() {
+        return text
+            .as_bytes()
+            .windows(term.len())
+            .position(|window| match case_sensitive {
+                true => window == term.as_bytes(),
+                false => window.eq_ignore_ascii_case(term.as_bytes()),
+            })
+            .map(|start| start..start + term.len());
+    }
+
+    text.char_indices().find_map(|(start, _)| {
+        let range = find_prefix(&text[start..], term, case_sensitive)?;
+        Some(start + range.start..start + range.end)
+    })
+}
+
+/// Find a term in a text, preferring a later occurrence starting a word over a
+/// first one inside a word when it scores higher.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The byte range of the best scoring occurrence of the term in the text.
+///
+fn find_word(text: &str, term: &str, case_sensitive: bool) -> Option<Range<usize>> {
+    let first = find_term(text, term, case_sensitive)?;
+    if is_word_boundary(text, first.start) {
+        return Some(first);
+    }
+
+    let word_start = text[first.start..]
+        .char_indices()
+        .skip(1)
+        .map(|(offset, _)| first.start + offset)
+        .filter(|&start| is_word_boundary(text, start))
+        .find_map(|start| {
+            let range = find_prefix(&text[start..], term, case_sensitive)?;
+            Some(start + range.start..start + range.end)
+        });
+    match word_start {
+        Some(range) if position_score(text, range.start) > position_score(text, first.start) => {
+            Some(range)
+        }
+        _ => Some(first),
+    }
+}
+
+/// Find a term at the start of a text.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The byte range of the term in the text, if the text starts with it.
+///
+fn find_prefix(text: &str, term: &str, case_sensitive: bool) -> Option<Range<usize>> {
+    let mut text_chars = text.chars();
+    let mut end = 0;
+    for term_char in term.chars() {
+        let text_char = text_chars.next()?;
+        if !chars_eq(text_char, term_char, case_sensitive) {
+            return None;
+        }
+        end += text_char.len_utf8();
+    }
+    Some(0..end)
+}
+
+/// Find a word of a search term in a text, without fuzzy matching.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `token`: The word of the search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The byte range of the word in the text, at its start or end if the word is
+/// anchored there, or spanning the whole text if anchored at both.
+///
+fn find_token(text: &str, token: &QueryToken, case_sensitive: bool) -> Option<Range<usize>> {
+    match (token.prefix, token.suffix) {
+        (true, true) => {
+            find_prefix(text, &token.text, case_sensitive).filter(|range| range.end == text.len())
+        }
+        (true, false) => find_prefix(text, &token.text, case_sensitive),
+        (false, true) => find_suffix(text, &token.text, case_sensitive),
+        (false, false) => find_word(text, &token.text, case_sensitive),
+    }
+}
+
+/// Find a term at the end of a text.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The byte range of the term in the text, if the text ends with it.
+///
+fn find_suffix(text: &str, term: &str, case_sensitive: bool) -> Option<Range<usize>> {
+    // Characters are compared one to one, so the term spans as many characters
+    let length = term.chars().count();
+    let start = match length {
+        0 => text.len(),
+        _ => text.char_indices().rev().nth(length - 1)?.0,
+    };
+    let range = find_prefix(&text[start..], term, case_sensitive)?;
+    Some(start + range.start..start + range.end)
+}
+
+/// Match a term as an ordered subsequence of the characters of a text.
+///
+/// # Arguments
+///
+/// * `text`: The text to search in.
+/// * `term`: The search term.
+/// * `case_sensitive`: Whether characters must have the same case to match.
+///
+/// # Returns
+///
+/// The match, or `None` if the term is not a subsequence of the text.
+///
+fn fuzzy_match(text: &str, term: &str, case_sensitive: bool) -> Option<TermMatch> {
+    let term_chars: Vec<char> = term.chars().collect();
+
+    // Find the end of the first complete subsequence
+    let mut matched = 0;
+    let mut end = 0;
+    for (pos, c) in text.char_indices() {
+        if chars_eq(c, term_chars[matched], case_sensitive) {
+            matched += 1;
+            if matched == term_chars.len() {
+                end = pos + c.len_utf8();
+                break;
+            }
+        }
+    }
+    if matched < term_chars.len() {
+        return None;
+    }
+
+    // Walk back from the end to find the tightest subsequence
+    let mut positions = Vec::with_capacity(term_chars.len());
+    let mut remaining = term_chars.iter().rev().peekable();
+    for (pos, c) in text[..end].char_indices().rev() {
+        match remaining.peek() {
+            Some(&&term_char) if chars_eq(c, term_char, case_sensitive) => {
+                positions.push(pos..pos + c.len_utf8());
+                remaining.next();
+            }
+            Some(_) => {}
+            None => break,
+        }
+    }
+    positions.reverse();
+
+    let first = positions.first()?.start;
+    let span = text[first..end].chars().count();
+    let tightness = term_chars.len() as f32 / span as f32;
+    let boundaries = positions
+        .iter()
+        .filter(|range| is_word_boundary(text, range.start))
+        .count() as f32
+        / term_chars.len() as f32;
+    let consecutive = match positions.len() {
+        1 => 1.0,
+        len => {
+            positions
+                .windows(2)
+                .filter(|pair| pair[0].end == pair[1].start)
+                .count() as f32
+                / (len - 1) as f32
+        }
+    };
+    let at_start = if first == 0 { 1.0 } else { 0.0 };
+
+    Some(TermMatch {
+        score: FUZZY_MAX_SCORE
+            * (0.4 * tightness + 0.2 * consecutive + 0.25 * boundaries + 0.15 * at_start),
+        ranges: merge_ranges(positions),
+    })
+}
+
+/// Compare two characters, ignoring case unless matching is case-sensitive.
+fn chars_eq(a: char, b: char, case_sensitive: bool) -> bool {
+    if a == b || case_sensitive {
+        return a == b;
+    }
+    if a.is_ascii() && b.is_ascii() {
+        return a.eq_ignore_ascii_case(&b);
+    }
+    a.to_lowercase().eq(b.to_lowercase())
+}
+
+/// Check whether a byte position starts a word, at the start of the text or
+/// after a space, `-`, `/`, `_` or `.`.
+fn is_word_boundary(text: &str, pos: usize) -> bool {
+    text[..pos]
+        .chars()
+        .next_back()
+        .is_none_or(|c| matches!(c, ' ' | '-' | '/' | '_' | '.'))
+}
+
+/// Merge adjacent or overlapping byte ranges, sorted by start.
+fn merge_ranges(ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Translate a ZSH glob pattern, as used in `HISTORY_IGNORE`, into a regex
+/// matching whole commands.
+///
+/// Supports `*`, `?`, bracket expressions (negated with `!` or `^`),
+/// alternation groups `(a|b)` and backslash escapes.
+///
+/// # Arguments
+///
+/// * `pattern`: The ZSH glob pattern.
+///
+/// # Returns
+///
+/// The regex, or an error if the pattern is malformed (e.g. unbalanced parentheses).
+///
+pub fn zsh_glob_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let mut regex = String::from("^(?s:");
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '(' => regex.push_str("(?:"),
+            ')' | '|' => regex.push(c),
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    regex.push_str(&regex::escape(&escaped.to_string()));
+                }
+            }
+            '[' => {
+                regex.push('[');
+                if let Some('!' | '^') = chars.clone().next() {
+                    regex.push('^');
+                    chars.next();
+                }
+                // A closing bracket right after the opening one is part of the set
+                if chars.clone().next() == Some(']') {
+                    regex.push_str("\\]");
+                    chars.next();
+                }
+                for c in chars.by_ref() {
+                    match c {
+                        ']' => break,
+                        '\\' | '[' | '&' | '~' => regex.push_str(&format!("\\{}", c)),
+                        _ => regex.push(c),
+                    }
+                }
+                regex.push(']');
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push_str(")$");
+    Regex::new(&regex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a query word.
+    fn token(text: &str, exact: bool, prefix: bool, suffix: bool) -> QueryToken {
+        QueryToken {
+            text: text.to_string(),
+            exact,
+            prefix,
+            suffix,
+        }
+    }
+
+    /// Check whether a term matches a text, case-insensitively and without fuzzy matching.
+    fn is_match(term: &str, text: &str) -> bool {
+        TermMatcher::new(term, false, CaseMatching::Smart, false)
+            .unwrap()
+            .matches(text)
+            .is_some()
+    }
+
+    #[test]
+    fn parse_quoted_words() {
+        assert_eq!(
+            parse_query("'git push' prod"),
+            [
+                token("git push", true, false, false),
+                token("prod", false, false, false)
+            ]
+        );
+        // A phrase runs to the end of the term when its quote is not closed
+        assert_eq!(
+            parse_query("'git push"),
+            [token("git push", true, false, false)]
+        );
+        // Quotes inside a phrase or a word are literal
+        assert_eq!(
+            parse_query("'it's here' don't"),
+            [
+                token("it's here", true, false, false),
+                token("don't", false, false, false)
+            ]
+        );
+        // Lone quotes are literal too
+        assert_eq!(
+            parse_query("' ls"),
+            [
+                token("'", false, false, false),
+                token("ls", false, false, false)
+            ]
+        );
+        assert_eq!(parse_query("'' ls"), [token("ls", false, false, false)]);
+        assert_eq!(parse_query("  "), []);
+    }
+
+    #[test]
+    fn match_quoted_phrases() {
+        assert!(is_match("'git push", "git push origin main"));
+        assert!(is_match("'git push", "GIT PUSH origin main"));
+        assert!(!is_match("'git push", "git stash && push"));
+        // Plain words may match apart, in any order
+        assert!(is_match("push git", "git push origin main"));
+        assert!(!is_match("'push git", "git push origin main"));
+        // Phrases compose with other words
+        assert!(is_match("main 'git push'", "git push origin main"));
+        assert!(!is_match("dev 'git push'", "git push origin main"));
+    }
+
+    /// Get the matched parts of a text, case-insensitively and without fuzzy matching.
+    fn matched<'a>(term: &str, text: &'a str) -> Option<Vec<&'a str>> {
+        let found = TermMatcher::new(term, false, CaseMatching::Smart, false)?.matches(text)?;
+        Some(found.ranges.into_iter().map(|range| &text[range]).collect())
+    }
+
+    #[test]
+    fn match_prefix_anchors() {
+        assert_eq!(parse_query("^git"), [token("git", false, true, false)]);
+        assert_eq!(matched("^git", "git push"), Some(vec!["git"]));
+        assert_eq!(matched("^git", "lazygit"), None);
+        assert_eq!(matched("^git", "echo git"), None);
+        // Each word carries its own anchor
+        assert_eq!(
+            matched("^git push", "git stash push"),
+            Some(vec!["git", "push"])
+        );
+        assert_eq!(
+            matched("push ^git", "git stash push"),
+            Some(vec!["git", "push"])
+        );
+        assert_eq!(matched("^push git", "git stash push"), None);
+        assert_eq!(matched("^'git st", "git stash"), Some(vec!["git st"]));
+        // Carets elsewhere, or alone, are literal
+        assert_eq!(matched("a^b", "echo a^b"), Some(vec!["a^b"]));
+        assert_eq!(matched("^", "echo ^"), Some(vec!["^"]));
+        assert_eq!(matched("^ git", "echo ^ git"), Some(vec!["^ git"]));
+    }
+
+    #[test]
+    fn match_suffix_anchors() {
+        assert_eq!(parse_query("log$"), [token("log", false, false, true)]);
+        assert_eq!(
+            matched("tail -f$", "ls | tail -f"),
+            Some(vec!["tail", "-f"])
+        );
+        assert_eq!(matched("tail -f$", "tail -f app.log"), None);
+        assert_eq!(
+            matched("main.rs$", "vim src/main.rs"),
+            Some(vec!["main.rs"])
+        );
+        assert_eq!(matched("main.rs$", "vim src/main.rs.orig"), None);
+        assert_eq!(
+            matched("vim main.rs$", "vim src/main.rs"),
+            Some(vec!["vim", "main.rs"])
+        );
+        assert_eq!(
+            matched("'src/main.rs'$", "vim src/main.rs"),
+            Some(vec!["src/main.rs"])
+        );
+        // Both anchors require the whole command
+        assert_eq!(parse_query("^make$"), [token("make", false, true, true)]);
+        assert_eq!(matched("^make$", "make"), Some(vec!["make"]));
+        assert_eq!(matched("^make$", "make test"), None);
+        assert_eq!(matched("^make$", "cmake"), None);
+        assert_eq!(
+            matched("^'git status'$", "git status"),
+            Some(vec!["git status"])
+        );
+        assert_eq!(matched("^'git status'$", "git status -s"), None);
+        // A lone dollar, or one inside a word, is literal
+        assert_eq!(parse_query("$"), [token("$", false, false, false)]);
+        assert_eq!(matched("$", "echo $HOME"), Some(vec!["$"]));
+        assert_eq!(matched("$HOME", "echo $HOME"), Some(vec!["$HOME"]));
+        assert_eq!(matched("a$b", "echo a$b"), Some(vec!["a$b"]));
+    }
+
+    /// Score the match of a term in a text, case-insensitively and without fuzzy matching.
+    fn score(term: &str, text: &str) -> f32 {
+        TermMatcher::new(term, false, CaseMatching::Smart, false)
+            .unwrap()
+            .matches(text)
+            .unwrap()
+            .score
+    }
+
+    #[test]
+    fn favor_matches_at_word_boundaries() {
+        assert_eq!(score("sh", "sh run.sh"), 1.0);
+        // In the program run, then starting a word, then inside a word
+        assert!(score("sh", "ssh host") > score("sh", "vim deploy.sh"));
+        assert!(score("sh", "vim deploy.sh") > score("sh", "vim ~/.zsh_history"));
+        assert!(score("sh", "ssh host") > score("sh", "zsh_history_cleanup --dry-run"));
+        for separator in [" ", "/", "-", "_", "."] {
+            let text = format!("cat a{}sh", separator);
+            assert!(
+                score("sh", &text) > score("sh", "cat ash"),
+                "{:?}",
+                separator
+            );
+        }
+        // A later occurrence starting a word is the one highlighted
+        let found = TermMatcher::new("sh", false, CaseMatching::Smart, false)
+            .unwrap()
+            .matches("cat zsh/deploy.sh")
+            .unwrap();
+        assert_eq!(found.ranges, vec![15..17]);
+    }
+
+    #[test]
+    fn score_by_position_not_length() {
+        let short = "docker compose up";
+        let long = format!("docker compose up {}", "--scale web=2 ".repeat(150));
+        // A match at the start scores the same whatever the length
+        assert_eq!(score("docker", short), 1.0);
+        assert_eq!(score("docker", &long), 1.0);
+        // Later in a long command, it scores a little less, not more
+        let short_score = score("compose", short);
+        let long_score = score("compose", &long);
+        assert!(long_score < short_score);
+        assert!(short_score - long_score <= LONG_COMMAND_PENALTY);
+        // Further matches score less, always above fuzzy matches
+        assert!(score("up", short) < short_score);
+        assert!(score("web", &long) > FUZZY_MAX_SCORE);
+    }
+
+    #[test]
+    fn match_case_by_mode() {
+        let matches = |term: &str, text: &str, case| {
+            TermMatcher::new(term, false, case, false)
+                .unwrap()
+                .matches(text)
+                .is_some()
+        };
+        // Smart-case ignores case until the term has an uppercase character
+        assert!(matches("cargo", "Cargo.toml", CaseMatching::Smart));
+        assert!(!matches("Cargo", "cargo.lock", CaseMatching::Smart));
+        assert!(!matches("cargo", "Cargo.toml", CaseMatching::Sensitive));
+        assert!(matches("Cargo", "cargo.lock", CaseMatching::Insensitive));
+        // Regexes follow the same modes
+        let regex = |term: &str, case| TermMatcher::new(term, true, case, false).unwrap();
+        assert!(regex("Carg.", CaseMatching::Smart)
+            .matches("cargo")
+            .is_none());
+        assert!(regex("Carg.", CaseMatching::Insensitive)
+            .matches("cargo")
+            .is_some());
+    }
+
+    #[test]
+    fn translate_zsh_globs() {
+        let matches =
+            |pattern: &str, command: &str| zsh_glob_regex(pattern).unwrap().is_match(command);
+
+        // Stars match any text, across lines, and patterns match whole commands
+        assert!(matches("ls*", "ls"));
+        assert!(matches("ls*", "ls -la\necho done"));
+        assert!(!matches("ls", "ls -la"));
+        assert!(!matches("ls*", "sudo ls"));
+        // Question marks match a single character
+        assert!(matches("cd ?", "cd /"));
+        assert!(!matches("cd ?", "cd .."));
+        assert!(matches("cd ??", "cd 日本"));
+        // Bracket expressions, negated with `!` or `^`, with a leading `]` in the set
+        assert!(matches("vi[mx] *", "vim notes"));
+        assert!(!matches("vi[mx] *", "vi notes"));
+        assert!(matches("[a-c]at", "bat"));
+        assert!(!matches("[!a-c]at", "bat"));
+        assert!(matches("[^a-c]at", "rat"));
+        assert!(matches("x[]]", "x]"));
+        assert!(matches("x[\\[&~]", "x&"));
+        assert!(matches("x[\\[&~]", "x["));
+        assert!(!matches("x[\\[&~]", "xa"));
+        // Alternation groups, also at the top level
+        assert!(matches("(ls|cd|pwd)", "pwd"));
+        assert!(matches("(ls|cd) *", "cd /tmp"));
+        assert!(!matches("(ls|cd)", "lscd"));
+        assert!(matches("ls|exit", "exit"));
+        // Escaped and regex metacharacters match literally
+        assert!(matches("echo \\*", "echo *"));
+        assert!(!matches("echo \\*", "echo hello"));
+        assert!(matches("a\\(b\\)", "a(b)"));
+        assert!(matches("1+1.{2}$", "1+1.{2}$"));
+        assert!(!matches("1+1.{2}$", "11x{2}"));
+
+        assert!(zsh_glob_regex("(ls|cd").is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Result};
+use log::debug;
+
+/// Clipboard tools tried in order, with their arguments to read the text from stdin.
+const CLIPBOARD_TOOLS: [(&str, &[&str]); 5] = [
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+    ("pbcopy", &[]),
+    ("clip", &[]),
+];
+
+/// Where the selected command is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Write `commandline\t<command>` to the output file, for the shell integration.
+    #[default]
+    File,
+    /// Print the command to the standard output.
+    Stdout,
+    /// Copy the command to the clipboard.
+    Clipboard,
+}
+
+/// Quote a string so that a POSIX shell reads it back as a single word.
+///
+/// Strings made only of safe characters are returned unchanged, others are
+/// wrapped in single quotes with embedded single quotes escaped.
+///
+/// # Arguments
+///
+/// * `text`: The string to quote.
+///
+/// # Returns
+///
+/// The shell-quoted string.
+///
+pub fn shell_escape(text: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+
+    if !text.is_empty() && text.chars().all(is_safe) {
+        return text.to_string();
+    }
+
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+/// Options controlling how the selected command is written.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    /// File to write the selected command to (optional).
+    pub file: Option<String>,
+    /// Shell-quote the selected command.
+    pub escape: bool,
+    /// Where the selected command is written.
+    pub mode: OutputMode,
+}
+
+/// Write the selected command according to the output options.
+///
+/// # Arguments
+///
+/// * `command`: The selected command.
+/// * `options`: The output options.
+///
+pub fn write_selection(command: &str, options: &OutputOptions) -> Result<()> {
+    let command = if options.escape {
+        shell_escape(command)
+    } else {
+        command.to_string()
+    };
+
+    match options.mode {
+        OutputMode::File => {
+            if let Some(output_file) = &options.file {
+                debug!("Write command to output file: {}", output_file);
+                let mut file = File::create(output_file)?;
+                writeln!(file, "commandline\t{}", command)?;
+            }
+        }
+        OutputMode::Stdout => {
+            debug!("Write command to stdout");
+            println!("{}", command);
+        }
+        OutputMode::Clipboard => copy_to_clipboard(&command)?,
+    }
+
+    Ok(())
+}
+
+/// Copy text to the clipboard with the first available clipboard tool.
+///
+/// # Arguments
+///
+/// * `text`: The text to copy.
+///
+fn copy_to_clipboard(text: &str) -> Result<()> {
+    if copy_with_tools(&CLIPBOARD_TOOLS, text) {
+        return Ok(());
+    }
+
+    bail!("no working clipboard tool found (wl-copy, xclip, xsel, pbcopy or clip)")
+}
+
+/// Copy text with the first of the given tools that runs and succeeds.
+///
+/// # Arguments
+///
+/// * `tools`: The tools to try in order, with their arguments.
+/// * `text`: The text to copy.
+///
+/// # Returns
+///
+/// Whether one of the tools copied the text.
+///
+fn copy_with_tools(tools: &[(&str, &[&str])], text: &str) -> bool {
+    for (program, args) in tools {
+        match pipe_to_tool(program, args, text) {
+            Ok(true) => {
+                debug!("Copied command to clipboard with {}", program);
+                return true;
+            }
+            Ok(false) => debug!("Failed to copy to clipboard with {}", program),
+            Err(e) => debug!("Failed to run {}: {}", program, e),
+        }
+    }
+
+    false
+}
+
+/// Run a tool with text written to its standard input.
+///
+/// # Arguments
+///
+/// * `program`: The program to run.
+/// * `args`: The program arguments.
+/// * `text`: The text written to the program.
+///
+/// # Returns
+///
+/// Whether the program exited successfully.
+///
+fn pipe_to_tool(program: &str, args: &[&str], text: &str) -> io::Result<bool> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Closing stdin once written lets the tool see the end of the text
+    let written = child
+        .stdin
+        .take()
+        .map_or(Ok(()), |mut stdin| stdin.write_all(text.as_bytes()));
+    if let Err(e) = written {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+
+    Ok(child.wait()?.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn try_next_clipboard_tool() {
+        // Large enough not to fit in the pipe buffer of a tool not reading it
+        let text = "x".repeat(1 << 20);
+        let tools: [(&str, &[&str]); 4] = [
+            ("termsearch-missing-tool", &[]),
+            ("false", &[]),
+            ("sh", &["-c", "exec 0<&-"]),
+            ("sh", &["-c", "cat >/dev/null"]),
+        ];
+        assert!(copy_with_tools(&tools, &text));
+        assert!(!copy_with_tools(&tools[..3], &text));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn report_write_errors() {
+        let text = "x".repeat(1 << 20);
+        assert!(pipe_to_tool("sh", &["-c", "exec 0<&-"], &text).is_err());
+        assert!(pipe_to_tool("cat", &[], "ls").unwrap());
+    }
+
+    #[test]
+    fn escape_shell_words() {
+        assert_eq!(shell_escape("ls"), "ls");
+        assert_eq!(shell_escape("src/main.rs:12"), "src/main.rs:12");
+        assert_eq!(shell_escape(""), "''");
+        assert_eq!(shell_escape("git commit -m fix"), "'git commit -m fix'");
+        assert_eq!(shell_escape("echo 'it'"), r"'echo '\''it'\'''");
+        assert_eq!(
+            shell_escape("echo \"$HOME\" | wc -l"),
+            "'echo \"$HOME\" | wc -l'"
+        );
+        assert_eq!(shell_escape("a\nb;`rm`*"), "'a\nb;`rm`*'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn escaped_words_read_back_unchanged() {
+        for text in [
+            "git commit -m 'fix: it''s'",
+            "echo \"$(date)\" \\ *",
+            "a\nb",
+            "",
+            "é ✓",
+        ] {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_escape(text)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn write_escaped_selection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        let options = OutputOptions {
+            file: Some(path.to_string_lossy().into_owned()),
+            escape: true,
+            mode: OutputMode::File,
+        };
+        write_selection("rm 'my file'", &options).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "commandline\t'rm '\\''my file'\\'''\n"
+        );
+    }
+}
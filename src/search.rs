@@ -1,139 +1,1694 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use log::debug;
+use rayon::prelude::*;
 
-use crate::history::CommandEntry;
+use crate::aliases::expand_alias;
+use crate::history::{CommandEntry, CommandMetadata, SourceLocation};
+use crate::matcher::{CaseMatching, TermMatcher};
 
-/// Weight for recency.
+/// Default weight for recency.
 const RECENCY_WEIGHT: f32 = 0.6;
-/// Weight for frequency.
+/// Default weight for frequency.
 const FREQUENCY_WEIGHT: f32 = 0.4;
+/// Default weight for the match score.
+const MATCH_WEIGHT: f32 = 1.0;
+/// Default time after which the recency of a command is halved (30 days).
+const RECENCY_HALF_LIFE: Duration = Duration::from_secs(30 * 86400);
+/// Number of unique commands from which they are scored in parallel. Below it,
+/// scoring serially takes a few milliseconds (2 to 5 ms for 20k commands with
+/// `cargo bench --bench search -- scoring`), too little to gain from threads.
+const PARALLEL_THRESHOLD: usize = 20_000;
+/// Number of entries scored between two checks of the time budget.
+const TIME_BUDGET_CHECK_INTERVAL: usize = 256;
+/// Maximum number of matching commands kept to narrow down the next search.
+/// Scoring a candidate costs about as much as scoring a command in a full
+/// search (15 ms for 100k of either with `cargo bench --bench search --
+/// narrowing`), so narrowing never loses time and the cap only bounds the
+/// memory kept between searches, to 800 KB.
+const MAX_CANDIDATES: usize = 100_000;
+/// Match score given to commands matched through an abbreviation.
+const ABBREVIATION_SCORE: f32 = 0.9;
+/// Bonus added to the match score when the matched text has the same case as the term.
+const EXACT_CASE_BONUS: f32 = 0.1;
+/// Factor of the score of commands run in the current directory or one of its parents.
+const DIRECTORY_BOOST: f32 = 1.5;
+
+/// Options controlling how commands are matched and ranked.
+#[derive(Debug, Clone)]
+pub struct SearchOptions {
+    /// Weight given to how recently a command was run.
+    pub recency_weight: f32,
+    /// Weight given to how often a command was run.
+    pub frequency_weight: f32,
+    /// Exponent of the match score, 1 ranking by match as much as by recency
+    /// and frequency, and 0 ignoring how well commands match.
+    pub match_weight: f32,
+    /// Time after which the recency of a command is halved.
+    pub recency_half_life: Duration,
+    /// Collapse runs of whitespace to a single space before comparing commands.
+    pub normalize_whitespace: bool,
+    /// Also strip trailing `;` and `&` before comparing commands.
+    pub normalize: bool,
+    /// Rank matches whose case agrees with the term above other matches.
+    pub prefer_exact_case: bool,
+    /// Match case-sensitively even when the term is all lowercase.
+    pub case_sensitive: bool,
+    /// Match the term as a regular expression.
+    pub regex: bool,
+    /// Also match the term as a subsequence of characters when it is not a substring.
+    pub fuzzy: bool,
+    /// Abbreviations mapped to the command names they stand for (e.g. `k` to `kubectl`).
+    pub abbreviations: HashMap<String, String>,
+    /// Shell aliases mapped to the text they expand to, also matched in their expanded form.
+    pub aliases: HashMap<String, String>,
+    /// Match against the shape of commands, with argument values masked.
+    pub shape: bool,
+    /// Count commands differing only by case as the same command.
+    pub fold_case_frequency: bool,
+    /// Maximum time spent scoring entries for a single search.
+    pub time_budget: Option<Duration>,
+    /// The current directory, ranking commands run in it or one of its parents higher.
+    pub directory: Option<PathBuf>,
+    /// Minimum total score of the commands kept when searching.
+    pub min_score: f32,
+    /// Number of unique commands from which they are scored in parallel.
+    pub parallel_threshold: usize,
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            recency_weight: RECENCY_WEIGHT,
+            frequency_weight: FREQUENCY_WEIGHT,
+            match_weight: MATCH_WEIGHT,
+            recency_half_life: RECENCY_HALF_LIFE,
+            normalize_whitespace: false,
+            normalize: false,
+            prefer_exact_case: false,
+            case_sensitive: false,
+            regex: false,
+            fuzzy: true,
+            abbreviations: HashMap::new(),
+            aliases: HashMap::new(),
+            shape: false,
+            fold_case_frequency: false,
+            time_budget: None,
+            directory: None,
+            min_score: 0.0,
+            parallel_threshold: PARALLEL_THRESHOLD,
+        }
+    }
+}
+
+impl SearchOptions {
+    /// Shift the balance between the recency and frequency weights, keeping their sum.
+    ///
+    /// # Arguments
+    ///
+    /// * `step`: Share of the sum moved to the recency weight (or to the
+    ///   frequency weight if negative).
+    ///
+    /// # Returns
+    ///
+    /// `false` if both weights are zero, so there is no balance to shift.
+    ///
+    pub fn shift_recency_balance(&mut self, step: f32) -> bool {
+        let total = self.recency_weight + self.frequency_weight;
+        if total <= 0.0 {
+            return false;
+        }
+
+        self.recency_weight = (self.recency_weight + step * total).clamp(0.0, total);
+        self.frequency_weight = total - self.recency_weight;
+        true
+    }
+
+    /// Get how the case of the search term is matched.
+    ///
+    /// # Returns
+    ///
+    /// Case-sensitive if forced, case-insensitive when matches with the case of
+    /// the term are only ranked first, and smart-case otherwise.
+    ///
+    pub fn case_matching(&self) -> CaseMatching {
+        if self.case_sensitive {
+            CaseMatching::Sensitive
+        } else if self.prefer_exact_case {
+            CaseMatching::Insensitive
+        } else {
+            CaseMatching::Smart
+        }
+    }
+}
+
+/// Collapse runs of whitespace into single spaces and trim both ends.
+///
+/// # Arguments
+///
+/// * `text`: The text to normalize.
+///
+/// # Returns
+///
+/// The normalized text.
+///
+pub fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Normalize a command so that trivially different variants compare equal.
+///
+/// Runs of whitespace are collapsed as by `normalize_whitespace`, and trailing
+/// `;` and `&` separators are stripped, so that `docker  ps;` becomes `docker ps`.
+///
+/// # Arguments
+///
+/// * `command`: The command to normalize.
+///
+/// # Returns
+///
+/// The normalized command.
+///
+pub fn normalize_command(command: &str) -> String {
+    let mut normalized = normalize_whitespace(command);
+    let len = normalized
+        .trim_end_matches(|c: char| c == ';' || c == '&' || c.is_whitespace())
+        .len();
+    normalized.truncate(len);
+    normalized
+}
+
+/// Placeholder replacing argument values in command shapes.
+const ARG_PLACEHOLDER: &str = "<arg>";
+
+/// Get the structural shape of a command, masking argument values.
+///
+/// The command name, the leading subcommands and the flags are kept, while
+/// every other token is replaced by `<arg>`, so that `docker run -v /a:/b nginx`
+/// becomes `docker run -v <arg> <arg>`.
+///
+/// # Arguments
+///
+/// * `command`: The command to get the shape of.
+///
+/// # Returns
+///
+/// The shape of the command.
+///
+pub fn command_shape(command: &str) -> String {
+    let is_subcommand = |token: &str| {
+        token.starts_with(|c: char| c.is_ascii_lowercase())
+            && token
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+
+    let mut in_subcommands = true;
+    let mut shape = Vec::new();
+
+    for (i, token) in command.split_whitespace().enumerate() {
+        if i == 0 {
+            shape.push(token.to_string());
+        } else if token.starts_with('-') && token.len() > 1 {
+            in_subcommands = false;
+            match token.split_once('=') {
+                Some((flag, _)) => shape.push(format!("{}={}", flag, ARG_PLACEHOLDER)),
+                None => shape.push(token.to_string()),
+            }
+        } else if in_subcommands && is_subcommand(token) {
+            shape.push(token.to_string());
+        } else {
+            in_subcommands = false;
+            shape.push(ARG_PLACEHOLDER.to_string());
+        }
+    }
+
+    shape.join(" ")
+}
+
+/// Get the form of a search term used for matching.
+///
+/// # Arguments
+///
+/// * `term`: The search term.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// The term itself, or its normalized form if whitespace is normalized.
+///
+fn query_form<'a>(term: &'a str, options: &SearchOptions) -> Cow<'a, str> {
+    if options.normalize_whitespace || options.normalize || options.shape {
+        Cow::Owned(normalize_whitespace(term))
+    } else {
+        Cow::Borrowed(term)
+    }
+}
+
+/// Get the form of a command used for matching and deduplication.
+///
+/// # Arguments
+///
+/// * `command`: The command as stored in the history.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// The command itself, or its shape or normalized form if enabled.
+///
+fn comparison_form<'a>(command: &'a str, options: &SearchOptions) -> Cow<'a, str> {
+    if options.shape {
+        Cow::Owned(command_shape(command))
+    } else if options.normalize {
+        Cow::Owned(normalize_command(command))
+    } else if options.normalize_whitespace {
+        Cow::Owned(normalize_whitespace(command))
+    } else {
+        Cow::Borrowed(command)
+    }
+}
+
+/// Get the key under which occurrences of a command are counted together.
+///
+/// Aliases are expanded first, so that `gst` and `git status` count as the same command.
+///
+/// # Arguments
+///
+/// * `command`: The command as stored in the history.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// The comparison form of the expanded command, lowercased if case folding is enabled.
+///
+pub fn frequency_key<'a>(command: &'a str, options: &SearchOptions) -> Cow<'a, str> {
+    let key = match expand_alias(command, &options.aliases) {
+        Cow::Borrowed(command) => comparison_form(command, options),
+        Cow::Owned(expanded) => Cow::Owned(comparison_form(&expanded, options).into_owned()),
+    };
+    if options.fold_case_frequency {
+        Cow::Owned(key.to_lowercase())
+    } else {
+        key
+    }
+}
+
+/// Check whether a term is a known abbreviation of a command's first token.
+///
+/// # Arguments
+///
+/// * `term`: The search term.
+/// * `command`: The command to check.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// `true` if the term abbreviates the command name.
+///
+pub fn matches_abbreviation(term: &str, command: &str, options: &SearchOptions) -> bool {
+    options
+        .abbreviations
+        .get(term.trim())
+        .is_some_and(|name| command.split_whitespace().next() == Some(name.as_str()))
+}
 
 /// Search commands based on a term.
 ///
+/// The history is indexed on each call, so a `SearchIndex` should be kept
+/// instead when searching the same history repeatedly.
+///
 /// # Arguments
 ///
 /// * `term`: The search term.
 /// * `history`: The list of command entries from the history.
 /// * `max_results`: Maximum number of results to return.
+/// * `options`: The search options.
 ///
 /// # Returns
 ///
 /// A vector of `CommandEntry` structs, sorted by their weighted score.
+///
 pub fn search_commands(
     term: &str,
     history: &[CommandEntry],
     max_results: usize,
+    options: &SearchOptions,
 ) -> Vec<CommandEntry> {
-    debug!("Search commands with term: {}", term);
+    SearchIndex::new(history, options)
+        .search(term, max_results, options)
+        .into_iter()
+        .map(|result| result.entry)
+        .collect()
+}
+
+/// Get the most frequent commands.
+///
+/// The history is indexed on each call, so a `SearchIndex` should be kept
+/// instead when listing the same history repeatedly.
+///
+/// # Arguments
+///
+/// * `history`: The list of command entries from the history.
+/// * `max_results`: Maximum number of results to return.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// A vector of `CommandEntry` structs, sorted by their weighted score.
+///
+pub fn get_frequent_commands(
+    history: &[CommandEntry],
+    max_results: usize,
+    options: &SearchOptions,
+) -> Vec<CommandEntry> {
+    SearchIndex::new(history, options)
+        .frequent(max_results, options)
+        .into_iter()
+        .map(|result| result.entry)
+        .collect()
+}
+
+/// A command matching a search, with how it matched.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    /// The matching command.
+    pub entry: CommandEntry,
+    /// The score the command was ranked by.
+    pub score: f32,
+    /// The parts the score was computed from.
+    pub components: ScoreComponents,
+    /// The byte ranges of the command matched by the search term.
+    pub match_ranges: Vec<Range<usize>>,
+}
+
+/// A command of the index matching a search term, with its score.
+#[derive(Debug, Clone, Copy)]
+struct ScoredCommand {
+    /// The position of the command in the index.
+    position: usize,
+    /// The total score of the command.
+    score: f32,
+    /// The parts the score was computed from.
+    components: ScoreComponents,
+}
+
+impl ScoredCommand {
+    /// Order commands by total score (descending), then by position in the
+    /// index so that the most recent comes first on ties.
+    fn rank(a: &Self, b: &Self) -> Ordering {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then(a.position.cmp(&b.position))
+    }
+}
+
+/// The commands matching a search term, kept to only score them again when
+/// the next search term extends it.
+#[derive(Debug, Clone)]
+pub struct SearchCandidates {
+    /// The search term the commands match.
+    term: String,
+    /// The positions of the matching commands in the index, most recent first.
+    positions: Vec<usize>,
+}
+
+/// The parts of the score of a command.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScoreComponents {
+    /// How well the command matches the search term, once weighed.
+    pub match_score: f32,
+    /// How recently the command was run.
+    pub recency: f32,
+    /// How often the command was run, relative to the most frequent command.
+    pub frequency: f32,
+}
+
+/// A unique command of the history, with its occurrences aggregated.
+#[derive(Debug, Clone)]
+struct IndexedCommand {
+    /// The command as it was last run.
+    command: String,
+    /// The form of the command used for matching.
+    key: String,
+    /// The form of the command used for matching once its alias is expanded, if it is one.
+    expanded: Option<String>,
+    /// The number of times the command was run.
+    count: usize,
+    /// The time the command was last run.
+    timestamp: DateTime<Utc>,
+    /// The details recorded when the command was last run.
+    metadata: Option<CommandMetadata>,
+    /// The location of the last occurrence of the command in the history file.
+    source: Option<SourceLocation>,
+    /// Whether the command was run in the current directory or one of its parents.
+    in_directory: bool,
+}
+
+/// The unique commands of the history, aggregated once so that each search
+/// only scores distinct commands.
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    /// The unique commands, most recently run first.
+    commands: Vec<IndexedCommand>,
+    /// The number of times the most frequent command was run.
+    max_count: usize,
+}
+
+impl SearchIndex {
+    /// Build the index of a history.
+    ///
+    /// # Arguments
+    ///
+    /// * `history`: The list of command entries from the history.
+    /// * `options`: The search options, defining which commands are counted together.
+    ///
+    /// # Returns
+    ///
+    /// The index of the unique commands of the history.
+    ///
+    pub fn new(history: &[CommandEntry], options: &SearchOptions) -> Self {
+        let start = Instant::now();
+
+        // Store the position of each unique command in the index, keyed by
+        // the commands themselves unless they are transformed
+        let mut positions: HashMap<Cow<str>, usize> = HashMap::with_capacity(history.len());
+        let mut commands: Vec<IndexedCommand> = Vec::new();
+
+        // Aggregate occurrences, most recent first so that the displayed form is the last one run
+        for entry in history.iter().rev() {
+            let bucket = frequency_key(&entry.command, options);
+            let in_directory = ran_in_directory(entry, options.directory.as_deref());
+            match positions.entry(bucket) {
+                Entry::Occupied(occupied) => {
+                    let indexed = &mut commands[*occupied.get()];
+                    indexed.count += entry.count;
+                    indexed.in_directory |= in_directory;
+
+                    // Keep the details of the occurrence run last, even when written earlier
+                    if entry.timestamp > indexed.timestamp {
+                        indexed.timestamp = entry.timestamp;
+                        indexed.metadata = entry.metadata.clone();
+                        indexed.source = entry.source;
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    let expanded = match expand_alias(&entry.command, &options.aliases) {
+                        Cow::Owned(expanded) => {
+                            Some(comparison_form(&expanded, options).into_owned())
+                        }
+                        Cow::Borrowed(_) => None,
+                    };
+                    vacant.insert(commands.len());
+                    commands.push(IndexedCommand {
+                        command: entry.command.clone(),
+                        key: comparison_form(&entry.command, options).into_owned(),
+                        expanded,
+                        count: entry.count,
+                        timestamp: entry.timestamp,
+                        metadata: entry.metadata.clone(),
+                        source: entry.source,
+                        in_directory,
+                    });
+                }
+            }
+        }
+
+        debug!(
+            "Indexed {} unique commands out of {} entries in {:?}",
+            commands.len(),
+            history.len(),
+            start.elapsed()
+        );
+        let max_count = commands
+            .iter()
+            .map(|indexed| indexed.count)
+            .max()
+            .unwrap_or(0);
+        Self {
+            commands,
+            max_count,
+        }
+    }
 
-    let term = term.to_lowercase();
+    /// Search commands based on a term.
+    ///
+    /// # Arguments
+    ///
+    /// * `term`: The search term.
+    /// * `max_results`: Maximum number of results to return.
+    /// * `options`: The search options.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `SearchResult` structs, sorted by their weighted score.
+    ///
+    pub fn search(
+        &self,
+        term: &str,
+        max_results: usize,
+        options: &SearchOptions,
+    ) -> Vec<SearchResult> {
+        self.narrow_search(term, max_results, options, &mut None)
+    }
 
-    // Store the best score for each unique command
-    let mut command_scores: HashMap<String, f32> = HashMap::new();
+    /// Search commands based on a term, only scoring the commands matching the
+    /// previous term when the term extends it.
+    ///
+    /// A command matching a term also matches the shorter terms it extends, as
+    /// long as the extension adds no abbreviation and the previous term has no
+    /// quote, anchor or surrounding whitespace whose meaning the extension could
+    /// change. The results are then the same as those of a full search.
+    ///
+    /// # Arguments
+    ///
+    /// * `term`: The search term.
+    /// * `max_results`: Maximum number of results to return.
+    /// * `options`: The search options, the same as for the previous term.
+    /// * `candidates`: The commands matching the previous term, replaced by the
+    ///   commands matching this term if there are not too many of them.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `SearchResult` structs, sorted by their weighted score.
+    ///
+    pub fn narrow_search(
+        &self,
+        term: &str,
+        max_results: usize,
+        options: &SearchOptions,
+        candidates: &mut Option<SearchCandidates>,
+    ) -> Vec<SearchResult> {
+        debug!("Search commands with term: {}", term);
 
-    // Calculate scores for each command
-    for entry in history.iter() {
-        // Calculate match score based on the search term
-        let match_score = match entry.command.to_lowercase().find(&term) {
-            Some(0) => 1.0, // Exact match at the start
-            Some(pos) => 0.5 - pos as f32 / entry.command.len() as f32, // Partial match
-            None => 0.0,    // No match
+        let previous = candidates
+            .take()
+            .filter(|candidates| can_narrow(&candidates.term, term, options));
+        let query = query_form(term, options);
+        let Some(matcher) = TermMatcher::new(
+            &query,
+            options.regex,
+            options.case_matching(),
+            options.fuzzy,
+        ) else {
+            return Vec::new();
         };
+        let now = Utc::now();
 
-        if match_score > 0.0 {
-            // Calculate recency weight (more recent = higher weight)
-            let seconds_ago = (Utc::now() - entry.timestamp).num_seconds() as f32;
-            let recency_weight = 1.0 / (1.0 + seconds_ago.log10());
+        // Only score the commands matching the previous term if narrowing down
+        if let Some(previous) = &previous {
+            debug!(
+                "Narrow down the {} commands matching {}",
+                previous.positions.len(),
+                previous.term
+            );
+        }
+        let count = previous
+            .as_ref()
+            .map_or(self.commands.len(), |previous| previous.positions.len());
+        let position = |scanned| {
+            previous
+                .as_ref()
+                .map_or(scanned, |previous| previous.positions[scanned])
+        };
 
-            // Calculate frequency weight (more frequent = higher weight)
-            let frequency_weight = command_scores
-                .get(&entry.command)
-                .map_or(1.0, |&score| score + 1.0);
+        // Calculate scores for each command, most recent first so that a time-limited
+        // search still considers the most likely candidates
+        let start = Instant::now();
+        let score = |scanned| {
+            let position = position(scanned);
+            let indexed = &self.commands[position];
+            let (score, components) =
+                self.score_command(indexed, &matcher, &query, now, options)?;
+            Some(ScoredCommand {
+                position,
+                score,
+                components,
+            })
+        };
+        let mut scored_commands = Vec::new();
+        let mut complete = true;
+        if options.time_budget.is_none() && count >= options.parallel_threshold {
+            // Scores are collected in the order of the commands, as when scored serially
+            scored_commands = (0..count).into_par_iter().filter_map(score).collect();
+        } else {
+            for scanned in 0..count {
+                if let Some(budget) = options.time_budget {
+                    if scanned % TIME_BUDGET_CHECK_INTERVAL == 0 && start.elapsed() > budget {
+                        debug!(
+                            "Search time-limited after {} of {} commands",
+                            scanned, count
+                        );
+                        complete = false;
+                        break;
+                    }
+                }
+                scored_commands.extend(score(scanned));
+            }
+        }
+        debug!(
+            "Scored {} matching commands in {:?}",
+            scored_commands.len(),
+            start.elapsed()
+        );
 
-            // Combine scores with weights
-            let total_score = match_score
-                * (RECENCY_WEIGHT * recency_weight + FREQUENCY_WEIGHT * frequency_weight);
+        // Keep every matching command to narrow down the next search
+        if complete && can_be_narrowed(options) && scored_commands.len() <= MAX_CANDIDATES {
+            *candidates = Some(SearchCandidates {
+                term: term.to_string(),
+                positions: scored_commands
+                    .iter()
+                    .map(|scored| scored.position)
+                    .collect(),
+            });
+        }
 
-            // Update the best score for the command
-            command_scores
-                .entry(entry.command.clone())
-                .and_modify(|e| *e = f32::max(*e, total_score))
-                .or_insert(total_score);
+        // Select the top results, then sort them by total score (descending),
+        // keeping the most recent first on ties
+        if scored_commands.len() > max_results {
+            scored_commands.select_nth_unstable_by(max_results, ScoredCommand::rank);
+            scored_commands.truncate(max_results);
         }
+        scored_commands.sort_unstable_by(ScoredCommand::rank);
+
+        // Only copy the top results, with the ranges matched in the displayed command
+        scored_commands
+            .into_iter()
+            .map(|scored| {
+                let indexed = &self.commands[scored.position];
+                SearchResult {
+                    entry: indexed.to_entry(),
+                    score: scored.score,
+                    components: scored.components,
+                    match_ranges: matcher
+                        .matches(&indexed.command)
+                        .map(|term_match| term_match.ranges)
+                        .unwrap_or_default(),
+                }
+            })
+            .collect()
     }
 
-    // Convert to a sorted vector
-    let mut sorted_commands: Vec<_> = command_scores.into_iter().collect();
-    sorted_commands.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    /// Score a command against a search term.
+    ///
+    /// # Arguments
+    ///
+    /// * `indexed`: The indexed command.
+    /// * `matcher`: The matcher of the search term.
+    /// * `query`: The search term, in the form it is matched in.
+    /// * `now`: The current time.
+    /// * `options`: The search options.
+    ///
+    /// # Returns
+    ///
+    /// The total score of the command and the parts of the score, or `None`
+    /// if it does not match or scores below the minimum score.
+    ///
+    fn score_command(
+        &self,
+        indexed: &IndexedCommand,
+        matcher: &TermMatcher,
+        query: &str,
+        now: DateTime<Utc>,
+        options: &SearchOptions,
+    ) -> Option<(f32, ScoreComponents)> {
+        let key = &indexed.key;
 
-    // Take the top results
-    sorted_commands
-        .into_iter()
-        .take(max_results)
-        .map(|(cmd, _)| CommandEntry {
-            command: cmd,
-            timestamp: DateTime::<Utc>::default(), // Timestamp not needed
-        })
-        .collect()
+        // Calculate match score based on the search term (substring then fuzzy, or regex)
+        let term_match = matcher.matches(key);
+        let mut match_score = term_match.as_ref().map_or(0.0, |m| m.score);
+
+        // Favor substring matches with the same case as the term
+        if let Some(term_match) = &term_match {
+            if options.prefer_exact_case
+                && term_match.ranges.len() == 1
+                && key.get(term_match.ranges[0].clone()) == Some(query)
+            {
+                match_score += EXACT_CASE_BONUS;
+            }
+        }
+
+        // Also match the command as its alias expands
+        if let Some(expanded) = &indexed.expanded {
+            if let Some(expanded_match) = matcher.matches(expanded) {
+                match_score = f32::max(match_score, expanded_match.score);
+            }
+        }
+
+        // Consider the term as an abbreviation of the command name
+        if !options.regex
+            && (matches_abbreviation(query, key, options)
+                || indexed
+                    .expanded
+                    .as_ref()
+                    .is_some_and(|expanded| matches_abbreviation(query, expanded, options)))
+        {
+            match_score = f32::max(match_score, ABBREVIATION_SCORE);
+        }
+
+        if match_score <= 0.0 {
+            return None;
+        }
+
+        // Weigh how well the command matches, as a power of the match score
+        if options.match_weight != 1.0 {
+            match_score = match_score.powf(options.match_weight);
+        }
+
+        // Combine scores with weights, or rank by match alone when both are disabled
+        let components = self.score_components(indexed, match_score, now, options);
+        let mut total_score = if is_match_only(options) {
+            match_score
+        } else {
+            match_score * weighted_score(&components, options)
+        };
+
+        // Favor commands run in the current directory
+        total_score *= directory_boost(indexed, options);
+
+        // Drop commands ranked too low to be worth listing
+        if total_score < options.min_score {
+            return None;
+        }
+
+        Some((total_score, components))
+    }
+
+    /// Get the parts of the score of a command.
+    ///
+    /// # Arguments
+    ///
+    /// * `indexed`: The indexed command.
+    /// * `match_score`: How well the command matches the search term, once weighed.
+    /// * `now`: The current time.
+    /// * `options`: The search options.
+    ///
+    /// # Returns
+    ///
+    /// The match score with the recency and frequency scores of the command.
+    ///
+    fn score_components(
+        &self,
+        indexed: &IndexedCommand,
+        match_score: f32,
+        now: DateTime<Utc>,
+        options: &SearchOptions,
+    ) -> ScoreComponents {
+        ScoreComponents {
+            match_score,
+            recency: recency_score(now - indexed.timestamp, options),
+            frequency: frequency_score(indexed, self.max_count),
+        }
+    }
+
+    /// Get the most frequent commands.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_results`: Maximum number of results to return.
+    /// * `options`: The search options.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `SearchResult` structs, sorted by their weighted score.
+    ///
+    pub fn frequent(&self, max_results: usize, options: &SearchOptions) -> Vec<SearchResult> {
+        debug!("Get frequent commands");
+
+        let now = Utc::now();
+
+        // Calculate weighted scores
+        let score = |indexed| {
+            let components = self.score_components(indexed, 1.0, now, options);
+            let score = weighted_score(&components, options) * directory_boost(indexed, options);
+            (indexed, score, components)
+        };
+        let mut scored_commands: Vec<_> = if self.commands.len() >= options.parallel_threshold {
+            self.commands.par_iter().map(score).collect()
+        } else {
+            self.commands.iter().map(score).collect()
+        };
+
+        // Sort by total score (descending), then by most recent
+        scored_commands.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(b.0.timestamp.cmp(&a.0.timestamp))
+        });
+
+        // Take the top results
+        scored_commands
+            .into_iter()
+            .take(max_results)
+            .map(|(indexed, score, components)| SearchResult {
+                entry: indexed.to_entry(),
+                score,
+                components,
+                match_ranges: Vec::new(),
+            })
+            .collect()
+    }
 }
 
-/// Get the most frequent commands.
+impl IndexedCommand {
+    /// Get the entry displayed for the command, with the time it was last run.
+    fn to_entry(&self) -> CommandEntry {
+        CommandEntry {
+            command: self.command.clone(),
+            timestamp: self.timestamp,
+            source: self.source,
+            metadata: self.metadata.clone(),
+            count: self.count,
+        }
+    }
+}
+
+/// Check whether a command was run in a directory or one of its parents.
 ///
-/// * `history`: The list of command entries from the history.
-/// * `max_results`: Maximum number of results to return.
+/// # Arguments
+///
+/// * `entry`: The command entry, with the directory it was run in if recorded.
+/// * `directory`: The directory, if known.
 ///
 /// # Returns
 ///
-/// A vector of `CommandEntry` structs, sorted by their weighted score.
-pub fn get_frequent_commands(history: &[CommandEntry], max_results: usize) -> Vec<CommandEntry> {
-    debug!("Get frequent commands");
-
-    // Store the frequency and most recent timestamp for each command
-    let mut command_data: HashMap<String, (usize, DateTime<Utc>)> = HashMap::new();
-
-    // Calculate frequency and recency
-    for entry in history.iter() {
-        command_data
-            .entry(entry.command.clone())
-            .and_modify(|(count, timestamp)| {
-                *count += 1;
-                if entry.timestamp > *timestamp {
-                    *timestamp = entry.timestamp;
-                }
+/// `true` if both directories are known and the command was run in the
+/// directory or one of its parents.
+///
+fn ran_in_directory(entry: &CommandEntry, directory: Option<&Path>) -> bool {
+    let cwd = entry
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.cwd.as_deref());
+    match (cwd, directory) {
+        (Some(cwd), Some(directory)) => directory.starts_with(cwd),
+        _ => false,
+    }
+}
+
+/// Check whether the commands matching a search can be narrowed down by a
+/// longer term, which requires the matching commands to be found whatever
+/// their score.
+///
+/// # Arguments
+///
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// `true` if the search term is not a regular expression and no minimum score is set.
+///
+fn can_be_narrowed(options: &SearchOptions) -> bool {
+    !options.regex && options.min_score <= 0.0
+}
+
+/// Check whether the commands matching a term include every command matching
+/// another term.
+///
+/// # Arguments
+///
+/// * `previous`: The previous search term.
+/// * `term`: The search term.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// `true` if the term extends the previous term without changing its meaning,
+/// so that only the commands matching the previous term need to be scored.
+///
+fn can_narrow(previous: &str, term: &str, options: &SearchOptions) -> bool {
+    // Quotes and anchors may start or end a word differently once extended, and
+    // a single word is matched with its surrounding whitespace until another follows
+    can_be_narrowed(options)
+        && term.starts_with(previous)
+        && previous.trim() == previous
+        && !previous.contains(['\'', '^', '$'])
+        && !options.abbreviations.contains_key(term.trim())
+}
+
+/// Check whether commands are ranked by how well they match alone, with both
+/// the recency and frequency weights disabled.
+///
+/// # Arguments
+///
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// `true` if both the recency and frequency weights are zero.
+///
+fn is_match_only(options: &SearchOptions) -> bool {
+    options.recency_weight == 0.0 && options.frequency_weight == 0.0
+}
+
+/// Get the factor of the score of a command, boosting commands run in the
+/// current directory unless ranking by match alone.
+fn directory_boost(indexed: &IndexedCommand, options: &SearchOptions) -> f32 {
+    if indexed.in_directory && !is_match_only(options) {
+        DIRECTORY_BOOST
+    } else {
+        1.0
+    }
+}
+
+/// Score how recently a command was run, halving with each half-life elapsed
+/// since, so that a command run yesterday clearly outranks one run months ago.
+///
+/// # Arguments
+///
+/// * `age`: The time elapsed since the command was run, negative for commands
+///   timestamped in the future by clock skew.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// The recency score, 1 for commands run just now or in the future and
+/// decreasing towards 0 with time.
+///
+fn recency_score(age: TimeDelta, options: &SearchOptions) -> f32 {
+    let seconds_ago = age.num_seconds().max(0) as f32;
+    0.5_f32.powf(seconds_ago / options.recency_half_life.as_secs_f32())
+}
+
+/// Score how often a command was run.
+///
+/// The frequency is the number of times the command was run on a log scale,
+/// relative to the most frequent command, so that it stays between 0 and 1
+/// like the recency and a frequent command does not outweigh its match score.
+///
+/// # Arguments
+///
+/// * `indexed`: The indexed command.
+/// * `max_count`: The number of times the most frequent command was run.
+///
+/// # Returns
+///
+/// The frequency score, between 0 and 1.
+///
+fn frequency_score(indexed: &IndexedCommand, max_count: usize) -> f32 {
+    (indexed.count as f32).ln_1p() / (max_count.max(1) as f32).ln_1p()
+}
+
+/// Combine how recently and how often a command was run.
+///
+/// # Arguments
+///
+/// * `components`: The parts of the score of the command.
+/// * `options`: The search options.
+///
+/// # Returns
+///
+/// The weighted recency and frequency score.
+///
+fn weighted_score(components: &ScoreComponents, options: &SearchOptions) -> f32 {
+    options.recency_weight * components.recency + options.frequency_weight * components.frequency
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Create a history entry run some minutes ago.
+    fn entry(command: &str, minutes_ago: i64) -> CommandEntry {
+        CommandEntry {
+            command: command.to_string(),
+            timestamp: Utc::now() - TimeDelta::minutes(minutes_ago),
+            source: None,
+            metadata: None,
+            count: 1,
+        }
+    }
+
+    /// Create a history entry run in a directory some minutes ago.
+    fn entry_in(command: &str, minutes_ago: i64, cwd: &str) -> CommandEntry {
+        CommandEntry {
+            metadata: Some(CommandMetadata {
+                cwd: Some(cwd.to_string()),
+                ..Default::default()
+            }),
+            ..entry(command, minutes_ago)
+        }
+    }
+
+    /// Get the commands of search results.
+    fn commands(results: &[SearchResult]) -> Vec<&str> {
+        results
+            .iter()
+            .map(|result| result.entry.command.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn rank_by_score_then_recency() {
+        // Repeated commands, all matching, with ties in score
+        let history: Vec<CommandEntry> = (0..3000)
+            .map(|i| entry(&format!("git checkout branch-{}", i % 700), 3000 - i))
+            .collect();
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
+
+        let all = index.search("git", usize::MAX, &options);
+        assert_eq!(all.len(), 700);
+        for pair in all.windows(2) {
+            assert!(
+                pair[0].score > pair[1].score
+                    || (pair[0].score == pair[1].score
+                        && pair[0].entry.timestamp >= pair[1].entry.timestamp),
+                "{:?} ranked before {:?}",
+                pair[0].entry.command,
+                pair[1].entry.command
+            );
+        }
+
+        // The top results are the first of the full ranking
+        let top = index.search("git", 10, &options);
+        assert_eq!(commands(&top), commands(&all[..10]));
+    }
+
+    /// Words the commands of the generated histories are made of.
+    const WORDS: &[&str] = &[
+        "git", "Git", "status", "push", "docker", "ps", "k", "kubectl", "get", "gst", "'quoted",
+        "a^b", "end$", "--force", "ssh", "prod-db",
+    ];
+
+    /// Characters the generated search terms are made of.
+    const TERM_CHARS: &[char] = &[
+        'g', 'i', 't', ' ', 's', 'p', 'G', 'k', 'o', 'd', 'c', '\'', '^', '$', '-',
+    ];
+
+    proptest! {
+        #[test]
+        fn narrowed_search_matches_full_search(
+            history in prop::collection::vec(
+                prop::collection::vec(prop::sample::select(WORDS), 1..4)
+                    .prop_map(|words| words.join(" ")),
+                1..40,
+            ),
+            term in prop::collection::vec(prop::sample::select(TERM_CHARS), 1..8),
+            fuzzy: bool,
+            case_sensitive: bool,
+            normalize: bool,
+            shape: bool,
+            fold_case_frequency: bool,
+        ) {
+            let options = SearchOptions {
+                fuzzy,
+                case_sensitive,
+                normalize,
+                shape,
+                fold_case_frequency,
+                abbreviations: HashMap::from([("k".to_string(), "kubectl".to_string())]),
+                aliases: HashMap::from([("gst".to_string(), "git status".to_string())]),
+                ..SearchOptions::default()
+            };
+            let len = history.len() as i64;
+            let history: Vec<CommandEntry> = history
+                .iter()
+                .zip(0..)
+                .map(|(command, i)| entry(command, len - i))
+                .collect();
+            let index = SearchIndex::new(&history, &options);
+
+            // Type the term one character at a time, as the UI searches
+            let term: String = term.into_iter().collect();
+            let mut candidates = None;
+            for end in 1..=term.len() {
+                let narrowed = index.narrow_search(&term[..end], usize::MAX, &options, &mut candidates);
+                let full = index.search(&term[..end], usize::MAX, &options);
+                prop_assert_eq!(commands(&narrowed), commands(&full), "term {:?}", &term[..end]);
+            }
+        }
+    }
+
+    #[test]
+    fn shift_balance_keeping_total() {
+        let mut options = SearchOptions {
+            recency_weight: 2.0,
+            frequency_weight: 1.0,
+            ..Default::default()
+        };
+        assert!(options.shift_recency_balance(0.1));
+        assert!((options.recency_weight - 2.3).abs() < 1e-5);
+        assert!((options.frequency_weight - 0.7).abs() < 1e-5);
+
+        assert!(options.shift_recency_balance(1.0));
+        assert_eq!(
+            (options.recency_weight, options.frequency_weight),
+            (3.0, 0.0)
+        );
+
+        // Ranking by match only has no balance to shift
+        let mut options = SearchOptions {
+            recency_weight: 0.0,
+            frequency_weight: 0.0,
+            ..Default::default()
+        };
+        assert!(!options.shift_recency_balance(0.1));
+        assert_eq!(
+            (options.recency_weight, options.frequency_weight),
+            (0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn fold_case_of_duplicates_for_frequency() {
+        let history = vec![
+            entry("Git status", 40),
+            entry("git status", 30),
+            entry("ls", 25),
+            entry("ls", 24),
+            entry("git Status", 10),
+        ];
+
+        let options = SearchOptions::default();
+        let results = SearchIndex::new(&history, &options).frequent(10, &options);
+        assert_eq!(
+            commands(&results),
+            ["ls", "git Status", "git status", "Git status"]
+        );
+
+        // The duplicates are counted together, shown as last run
+        let options = SearchOptions {
+            fold_case_frequency: true,
+            ..Default::default()
+        };
+        let results = SearchIndex::new(&history, &options).frequent(10, &options);
+        assert_eq!(commands(&results), ["git Status", "ls"]);
+        assert_eq!(results[0].entry.count, 3);
+        assert_eq!(results[0].components.frequency, 1.0);
+        assert!(results[1].components.frequency < 1.0);
+
+        // Matching still follows the case sensitivity of the search
+        let results = SearchIndex::new(&history, &options).search("status", 10, &options);
+        assert_eq!(commands(&results), ["git Status"]);
+    }
+
+    #[test]
+    fn mask_argument_values() {
+        assert_eq!(
+            command_shape("docker run -v /a:/b nginx"),
+            "docker run -v <arg> <arg>"
+        );
+        assert_eq!(
+            command_shape("git commit --message=fix -a"),
+            "git commit --message=<arg> -a"
+        );
+        assert_eq!(
+            command_shape("kubectl get pods -n kube-system logs"),
+            "kubectl get pods -n <arg> <arg>"
+        );
+        assert_eq!(command_shape("cp Makefile /tmp"), "cp <arg> <arg>");
+        assert_eq!(command_shape("  ls   -  "), "ls <arg>");
+        assert_eq!(command_shape(""), "");
+    }
+
+    #[test]
+    fn search_by_shape() {
+        let history = vec![
+            entry("docker run -v /data:/data postgres", 30),
+            entry("docker ps -a", 20),
+            entry("docker run --rm alpine", 10),
+        ];
+        let options = SearchOptions {
+            shape: true,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+
+        // Values are not matched, while the command keeps them for display
+        let results = index.search("docker run -v <arg>", 10, &options);
+        assert_eq!(commands(&results), ["docker run -v /data:/data postgres"]);
+        assert!(index.search("postgres", 10, &options).is_empty());
+    }
+
+    #[test]
+    fn rank_by_recency_only() {
+        // The most run command is the oldest one
+        let mut history: Vec<CommandEntry> = (0..10).map(|i| entry("git pull", 100 + i)).collect();
+        history.extend([entry("git push", 20), entry("git status", 5)]);
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions {
+            recency_weight: 1.0,
+            frequency_weight: 0.0,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+
+        assert_eq!(
+            commands(&index.search("git", 10, &options)),
+            ["git status", "git push", "git pull"]
+        );
+        assert_eq!(
+            commands(&index.frequent(10, &options)),
+            ["git status", "git push", "git pull"]
+        );
+
+        // The default weights favor the most run command
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
+        assert_eq!(commands(&index.frequent(1, &options)), ["git pull"]);
+    }
+
+    #[test]
+    fn merge_normalized_commands() {
+        assert_eq!(normalize_command("  docker   ps  -a ;"), "docker ps -a");
+        assert_eq!(normalize_command("sleep 10 &"), "sleep 10");
+
+        let history = vec![
+            entry("docker  ps", 50),
+            entry("docker ps;", 40),
+            entry("make", 35),
+            entry("make", 34),
+            entry("docker ps ", 30),
+        ];
+        let options = SearchOptions::default();
+        let results = SearchIndex::new(&history, &options).frequent(10, &options);
+        assert_eq!(results.len(), 4);
+
+        // The variants count together, shown as last run
+        let options = SearchOptions {
+            normalize: true,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+        let results = index.frequent(10, &options);
+        assert_eq!(commands(&results), ["docker ps ", "make"]);
+        assert_eq!(results[0].entry.count, 3);
+        assert_eq!(results[1].entry.count, 2);
+        assert_eq!(
+            commands(&index.search("docker ps", 10, &options)),
+            ["docker ps "]
+        );
+    }
+
+    #[test]
+    fn count_occurrences_for_frequency() {
+        // Run at the same times, so only their counts differ
+        let mut history: Vec<CommandEntry> = (0..10).map(|i| entry("git status", 60 - i)).collect();
+        history.extend(
+            (0..10)
+                .filter(|i| i % 3 == 0)
+                .map(|i| entry("git stash", 60 - i)),
+        );
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
+
+        let results = index.search("git st", 10, &options);
+        assert_eq!(commands(&results), ["git status", "git stash"]);
+        assert_eq!(results[0].entry.count, 10);
+        assert_eq!(results[1].entry.count, 4);
+        assert_eq!(results[0].components.frequency, 1.0);
+        let expected = 5_f32.ln() / 11_f32.ln();
+        assert!((results[1].components.frequency - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn score_recent_and_future_commands() {
+        let options = SearchOptions::default();
+        let score = |seconds| recency_score(TimeDelta::seconds(seconds), &options);
+
+        assert_eq!(score(0), 1.0);
+        assert_eq!(score(-3600), 1.0);
+        assert!(score(1) <= 1.0 && score(1) > 0.99);
+        assert!(score(3600) < score(1) && score(3600) > score(86400));
+        // Never increasing with age, and always a number
+        let ages = [
+            -86400,
+            -1,
+            0,
+            1,
+            2,
+            59,
+            3600,
+            86400,
+            30 * 86400,
+            3650 * 86400,
+        ];
+        for pair in ages.windows(2) {
+            assert!(score(pair[0]) >= score(pair[1]), "{:?}", pair);
+        }
+        assert!(ages.iter().all(|&age| score(age).is_finite()));
+
+        // Commands run just now or timestamped ahead by clock skew rank first,
+        // the latest timestamp breaking the tie
+        let history = vec![
+            entry("make test", 7 * 24 * 60),
+            entry("make build", 0),
+            entry("make clean", -5),
+        ];
+        let index = SearchIndex::new(&history, &options);
+        assert_eq!(
+            commands(&index.search("make", 10, &options)),
+            ["make clean", "make build", "make test"]
+        );
+    }
+
+    #[test]
+    fn rank_without_frequency() {
+        let defaults = SearchOptions::default();
+        assert_eq!(
+            (
+                defaults.recency_weight,
+                defaults.frequency_weight,
+                defaults.match_weight
+            ),
+            (0.6, 0.4, 1.0)
+        );
+
+        // Equal matches, the most run commands being the oldest
+        let mut history: Vec<CommandEntry> =
+            (0..20).map(|i| entry("cargo test", 300 + i)).collect();
+        history.extend((0..5).map(|i| entry("cargo build", 200 + i)));
+        history.push(entry("cargo run", 100));
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions {
+            frequency_weight: 0.0,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+
+        let results = index.search("cargo", 10, &options);
+        assert_eq!(
+            commands(&results),
+            ["cargo run", "cargo build", "cargo test"]
+        );
+        for result in &results {
+            let expected = result.components.match_score * 0.6 * result.components.recency;
+            assert!((result.score - expected).abs() < 1e-6);
+        }
+        assert_eq!(
+            commands(&index.frequent(10, &options)),
+            ["cargo run", "cargo build", "cargo test"]
+        );
+
+        // Without match weight, a weaker match ranks by recency too
+        let options = SearchOptions {
+            frequency_weight: 0.0,
+            match_weight: 0.0,
+            ..Default::default()
+        };
+        let history = vec![
+            entry("cargo test", 300),
+            entry("cargo nextest run", 200),
+            entry("tar -xf test.tar", 100),
+        ];
+        let index = SearchIndex::new(&history, &options);
+        assert_eq!(
+            commands(&index.search("test", 10, &options)),
+            ["tar -xf test.tar", "cargo nextest run", "cargo test"]
+        );
+    }
+
+    #[test]
+    fn return_latest_timestamps() {
+        // Out of order, as read from several sessions
+        let history = vec![
+            entry("ls", 30),
+            entry("ls", 5),
+            entry("pwd", 20),
+            entry("ls", 60),
+            entry("pwd", 40),
+        ];
+        let latest = |command: &str| {
+            history
+                .iter()
+                .filter(|entry| entry.command == command)
+                .map(|entry| entry.timestamp)
+                .max()
+                .unwrap()
+        };
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
+
+        let results = index.search("", 10, &options);
+        assert_eq!(results.len(), 2);
+        for results in [
+            results,
+            index.frequent(10, &options),
+            index.search("s", 10, &options),
+        ] {
+            for result in &results {
+                assert_eq!(result.entry.timestamp, latest(&result.entry.command));
+            }
+        }
+    }
+
+    #[test]
+    fn halve_recency_every_half_life() {
+        let options = SearchOptions {
+            recency_half_life: Duration::from_secs(7 * 86400),
+            ..Default::default()
+        };
+        let half_life = TimeDelta::days(7);
+
+        assert_eq!(recency_score(TimeDelta::zero(), &options), 1.0);
+        assert!((recency_score(half_life, &options) - 0.5).abs() < 1e-6);
+        assert!((recency_score(half_life * 2, &options) - 0.25).abs() < 1e-6);
+        assert!((recency_score(half_life / 2, &options) - 0.5_f32.sqrt()).abs() < 1e-6);
+
+        // The default half-life is 30 days
+        let options = SearchOptions::default();
+        assert!((recency_score(TimeDelta::days(30), &options) - 0.5).abs() < 1e-6);
+
+        // Searches and frequent commands share the same recency
+        let history = vec![entry("ls", 14 * 24 * 60)];
+        let index = SearchIndex::new(&history, &options);
+        let searched = index.search("ls", 1, &options)[0].components.recency;
+        let frequent = index.frequent(1, &options)[0].components.recency;
+        assert!((searched - frequent).abs() < 1e-4);
+        assert!((searched - 0.5_f32.powf(14.0 / 30.0)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn rank_exact_case_first() {
+        let history = vec![entry("cat Cargo.toml", 60), entry("cat cargo.log", 5)];
+        let options = SearchOptions {
+            prefer_exact_case: true,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+
+        // The term with an uppercase character still matches the other case
+        let results = index.search("Cargo", 10, &options);
+        assert_eq!(commands(&results), ["cat Cargo.toml", "cat cargo.log"]);
+        assert!(results[0].score > results[1].score);
+
+        let results = index.search("cargo", 10, &options);
+        assert_eq!(commands(&results), ["cat cargo.log", "cat Cargo.toml"]);
+    }
+
+    #[test]
+    fn combine_case_options() {
+        let option = |prefer_exact_case, case_sensitive| SearchOptions {
+            prefer_exact_case,
+            case_sensitive,
+            fuzzy: false,
+            ..Default::default()
+        };
+        assert_eq!(option(false, false).case_matching(), CaseMatching::Smart);
+        assert_eq!(
+            option(true, false).case_matching(),
+            CaseMatching::Insensitive
+        );
+        assert_eq!(option(false, true).case_matching(), CaseMatching::Sensitive);
+        assert_eq!(option(true, true).case_matching(), CaseMatching::Sensitive);
+
+        // Forcing the case excludes other cases, even when preferring the term's case
+        let history = vec![entry("cat Cargo.toml", 60), entry("cat cargo.log", 5)];
+        let options = option(true, true);
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("cargo", 10, &options);
+        assert_eq!(commands(&results), ["cat cargo.log"]);
+
+        // Smart-case alone excludes them once the term has an uppercase character
+        let options = option(false, false);
+        let results = index.search("Cargo", 10, &options);
+        assert_eq!(commands(&results), ["cat Cargo.toml"]);
+        let results = index.search("cargo", 10, &options);
+        assert_eq!(commands(&results), ["cat cargo.log", "cat Cargo.toml"]);
+    }
+
+    #[test]
+    fn rank_by_match_only() {
+        // The weaker match was run more often, more recently and in the current directory
+        let mut history: Vec<CommandEntry> = (0..10)
+            .map(|i| entry_in("cargo build --release", 1 + i, "/project"))
+            .collect();
+        history.push(entry("build --all", 10_000));
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions {
+            recency_weight: 0.0,
+            frequency_weight: 0.0,
+            directory: Some(PathBuf::from("/project")),
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+
+        let results = index.search("build", 10, &options);
+        assert_eq!(commands(&results), ["build --all", "cargo build --release"]);
+        // Scores are the match scores, without the directory boost
+        let matcher = TermMatcher::new("build", false, CaseMatching::Smart, true).unwrap();
+        for result in &results {
+            let term_match = matcher.matches(&result.entry.command).unwrap();
+            assert_eq!(result.score, term_match.score);
+        }
+    }
+
+    #[test]
+    fn boost_commands_run_in_current_directory() {
+        // Equal matches run as often, the one from elsewhere more recently
+        let history = vec![
+            entry_in("make test", 30, "/home/user/project"),
+            entry_in("make lint", 20, "/srv/other"),
+        ];
+        let options = SearchOptions {
+            directory: Some(PathBuf::from("/home/user/project/src")),
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("make", 10, &options);
+        assert_eq!(commands(&results), ["make test", "make lint"]);
+        assert!(results[0].score > results[1].score);
+    }
+
+    #[test]
+    fn skip_directory_boost_when_unknown() {
+        let unboosted = |results: &[SearchResult], options: &SearchOptions| {
+            results.iter().all(|result| {
+                let components = &result.components;
+                result.score == components.match_score * weighted_score(components, options)
             })
-            .or_insert((1, entry.timestamp));
+        };
+
+        // The current directory is unknown
+        let history = vec![
+            entry_in("make test", 30, "/home/user/project"),
+            entry_in("make lint", 20, "/srv/other"),
+        ];
+        let options = SearchOptions {
+            directory: None,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("make", 10, &options);
+        assert_eq!(commands(&results), ["make lint", "make test"]);
+        assert!(unboosted(&results, &options));
+
+        // The directories the commands were run in are unknown
+        let history = vec![entry("make test", 30), entry("make lint", 20)];
+        let options = SearchOptions {
+            directory: Some(PathBuf::from("/home/user/project")),
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("make", 10, &options);
+        assert_eq!(commands(&results), ["make lint", "make test"]);
+        assert!(unboosted(&results, &options));
     }
 
-    // Convert to a vector and calculate weighted scores
-    let mut scored_commands: Vec<_> = command_data
-        .into_iter()
-        .map(|(cmd, (count, timestamp))| {
-            // Calculate recency weight (more recent = higher weight)
-            let seconds_ago = (Utc::now() - timestamp).num_seconds() as f32;
-            let recency_weight = 1.0 / (1.0 + seconds_ago.log10());
+    #[test]
+    fn match_and_merge_commands_by_whitespace() {
+        let history = vec![
+            entry("git  commit   -m fix", 50),
+            entry("git commit -m fix", 40),
+            entry("git commit\t-m fix ", 30),
+            entry("git status", 20),
+        ];
+        let options = SearchOptions {
+            fuzzy: false,
+            ..Default::default()
+        };
+        let index = SearchIndex::new(&history, &options);
+        assert_eq!(index.search("commit", 10, &options).len(), 3);
+        let results = index.search("'commit -m", 10, &options);
+        assert_eq!(commands(&results), ["git commit -m fix"]);
 
-            // Calculate frequency weight (more frequent = higher weight)
-            let frequency_weight = count as f32;
+        // Spacing differences in the term and the commands are ignored
+        let options = SearchOptions {
+            normalize_whitespace: true,
+            ..options
+        };
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("'commit  -m", 10, &options);
+        assert_eq!(commands(&results), ["git commit\t-m fix "]);
+        assert_eq!(results[0].entry.count, 3);
+        assert_eq!(index.search("commit", 10, &options).len(), 1);
+    }
+
+    #[test]
+    fn rank_rare_exact_match_above_frequent_weak_match() {
+        // A fuzzy match run fifty times, and an exact match run once, as recently
+        let mut history: Vec<CommandEntry> = (0..50)
+            .map(|i| entry("docker push registry/app:latest", 10 + i))
+            .collect();
+        history.push(entry("docker ps", 10));
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
 
-            // Combine scores with weights
-            let total_score = RECENCY_WEIGHT * recency_weight + FREQUENCY_WEIGHT * frequency_weight;
+        let results = index.search("docker ps", 10, &options);
+        assert_eq!(
+            commands(&results),
+            ["docker ps", "docker push registry/app:latest"]
+        );
+        assert!(results[0].components.frequency < results[1].components.frequency);
+    }
 
-            (cmd, total_score)
-        })
-        .collect();
+    #[test]
+    fn rank_frequent_match_above_rare_weaker_match() {
+        // The exact match run fifty times, and a later substring match run once
+        let mut history: Vec<CommandEntry> = (0..50).map(|i| entry("make", 60 + i)).collect();
+        history.push(entry("cmake --build build", 10));
+        history.sort_by_key(|entry| entry.timestamp);
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
 
-    // Sort by total score (descending)
-    scored_commands.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        let results = index.search("make", 10, &options);
+        assert_eq!(commands(&results), ["make", "cmake --build build"]);
+        assert_eq!(results[0].entry.count, 50);
 
-    // Take the top results
-    scored_commands
-        .into_iter()
-        .take(max_results)
-        .map(|(cmd, _)| CommandEntry {
-            command: cmd,
-            timestamp: DateTime::<Utc>::default(), // Timestamp not needed
-        })
-        .collect()
+        // Among equal matches, the frequent command also outranks the more recent one
+        history.push(entry("make clean", 5));
+        history.sort_by_key(|entry| entry.timestamp);
+        let index = SearchIndex::new(&history, &options);
+        let results = index.search("make", 10, &options);
+        assert_eq!(commands(&results)[..2], ["make", "make clean"]);
+    }
+
+    #[test]
+    fn search_through_function_wrappers() {
+        let history = vec![
+            entry("git push", 30),
+            entry("git status", 20),
+            entry("git status", 10),
+        ];
+        let options = SearchOptions::default();
+        let index = SearchIndex::new(&history, &options);
+        let entries = |results: Vec<SearchResult>| -> Vec<String> {
+            results
+                .into_iter()
+                .map(|result| result.entry.command)
+                .collect()
+        };
+        let found: Vec<String> = search_commands("git", &history, 10, &options)
+            .into_iter()
+            .map(|entry| entry.command)
+            .collect();
+        assert_eq!(found, entries(index.search("git", 10, &options)));
+        assert_eq!(found, ["git status", "git push"]);
+
+        let frequent = get_frequent_commands(&history, 1, &options);
+        assert_eq!(frequent.len(), 1);
+        assert_eq!(frequent[0].command, "git status");
+        assert_eq!(frequent[0].count, 2);
+    }
 }
@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use anyhow::Result;
+use log::debug;
+use rusqlite::types::ValueRef;
+use rusqlite::{Connection, OpenFlags};
+
+use crate::error::TermsearchError;
+
+/// Run a read-only query on a SQLite database.
+///
+/// # Arguments
+///
+/// * `path`: The path to the database.
+/// * `sql`: The query to run.
+///
+/// # Returns
+///
+/// The rows returned by the query, as text fields (empty for `NULL`, numbers
+/// in decimal).
+///
+pub fn query(path: &Path, sql: &str) -> Result<Vec<Vec<String>>> {
+    debug!("Query database {:?}: {}", path, sql);
+
+    let database_error = |e: rusqlite::Error| TermsearchError::Database {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    };
+
+    let connection = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(database_error)?;
+    let mut statement = connection.prepare(sql).map_err(database_error)?;
+    let column_count = statement.column_count();
+
+    let mut rows = statement.query([]).map_err(database_error)?;
+    let mut fields = Vec::new();
+    while let Some(row) = rows.next().map_err(database_error)? {
+        let values = (0..column_count)
+            .map(|i| row.get_ref(i).map(value_to_string))
+            .collect::<rusqlite::Result<_>>()
+            .map_err(database_error)?;
+        fields.push(values);
+    }
+
+    Ok(fields)
+}
+
+/// Convert a field of a row to text.
+///
+/// # Arguments
+///
+/// * `value`: The value of the field.
+///
+/// # Returns
+///
+/// The text of the value, or an empty string for `NULL`.
+///
+fn value_to_string(value: ValueRef) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => f.to_string(),
+        ValueRef::Text(text) | ValueRef::Blob(text) => String::from_utf8_lossy(text).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_typed_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE history (timestamp INTEGER, duration REAL, cwd TEXT, command BLOB);
+                 INSERT INTO history VALUES (1700000000, 1.5, NULL, 'ls');
+                 INSERT INTO history VALUES (1700000001, NULL, '/tmp', X'666F720A646F6E65');",
+            )
+            .unwrap();
+
+        let rows = query(&path, "SELECT * FROM history ORDER BY timestamp").unwrap();
+        assert_eq!(
+            rows,
+            [
+                ["1700000000", "1.5", "", "ls"],
+                ["1700000001", "", "/tmp", "for\ndone"],
+            ]
+        );
+    }
+
+    #[test]
+    fn report_query_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.db");
+        Connection::open(&path).unwrap();
+
+        let error = query(&path, "SELECT command FROM history").unwrap_err();
+        assert!(error.to_string().contains("no such table"));
+        assert!(query(&dir.path().join("missing.db"), "SELECT 1").is_err());
+    }
+}
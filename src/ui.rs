@@ -1,17 +1,50 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::{stdout, Stdout, Write};
+use std::ops::Range;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
 use crossterm::{
     cursor::{self, Hide, Show},
-    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    event::{
+        self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind,
+        KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
     execute, queue,
     style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
     terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use log::debug;
 
-use crate::history::CommandEntry;
-use crate::search::{get_frequent_commands, search_commands};
+use crate::aliases::expand_alias;
+use crate::error::TermsearchError;
+use crate::files::{extract_paths, find_files};
+use crate::history::{
+    delete_history_entries, read_history, CommandEntry, HistoryFollower, HistoryLoadOptions,
+    HistoryUpdate, Shell,
+};
+use crate::man::read_man_page;
+use crate::matcher::TermMatcher;
+use crate::search::{
+    frequency_key, matches_abbreviation, ScoreComponents, SearchCandidates, SearchIndex,
+    SearchOptions, SearchResult,
+};
+
+/// Interval between two checks of the followed history file.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Share of the total weight moved between recency and frequency in tuning mode.
+const TUNE_STEP: f32 = 0.05;
+
+/// Minimum rendered width for the column showing when commands were run.
+const MIN_WIDTH_FOR_AGES: usize = 40;
+
+/// Minimum rendered width for the column showing the score of commands.
+const MIN_WIDTH_FOR_SCORES: usize = 80;
 
 /// Actions after handling a key event.
 enum KeyAction {
@@ -23,20 +56,154 @@ enum KeyAction {
     Exit,
 }
 
+/// Position of the selection when results are (re)computed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum InitialSelection {
+    /// Select the best match, at the top of the list.
+    #[default]
+    First,
+    /// Select the last match, at the bottom of the list.
+    Last,
+}
+
+/// The way matches are listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ResultsView {
+    /// The best matches, ranked by score.
+    Scored,
+    /// Every matching history entry, most recent first.
+    Chronological,
+}
+
+/// The content shown in the preview pane below the matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Preview {
+    /// The manual page of the selected command.
+    Man,
+    /// The files matching the paths in the selected command.
+    Files,
+}
+
+/// Options controlling the terminal UI behavior.
+#[derive(Debug, Clone, Default)]
+pub struct UiOptions {
+    /// Capture mouse events so results can be selected by clicking.
+    pub mouse: bool,
+    /// Position of the selection when results are (re)computed.
+    pub initial_selection: InitialSelection,
+    /// Allow adjusting the recency/frequency balance with Alt+'+' and Alt+'-'.
+    pub tune: bool,
+    /// Allow showing the manual page of the selected command with F1.
+    pub man: bool,
+    /// Allow listing files matching the paths of the selected command with F2.
+    pub find_files: bool,
+    /// Maximum number of columns used to render a command (optional).
+    pub max_width: Option<u16>,
+    /// Show the score of each command and the parts it was computed from.
+    pub show_scores: bool,
+}
+
+/// Clamp a selection and scroll offset to the matches and viewport.
+///
+/// The selected index always lands on an existing match, and the scroll
+/// offset never leaves blank rows at the bottom of the viewport while
+/// keeping the selected match visible.
+///
+/// # Arguments
+///
+/// * `selected_index`: The index of the selected match.
+/// * `scroll_offset`: The index of the first match displayed.
+/// * `num_matches`: The number of matches.
+/// * `viewport_height`: The number of rows available to display matches.
+///
+/// # Returns
+///
+/// The clamped selected index and scroll offset.
+///
+fn clamp_selection(
+    selected_index: usize,
+    scroll_offset: usize,
+    num_matches: usize,
+    viewport_height: usize,
+) -> (usize, usize) {
+    if num_matches == 0 || viewport_height == 0 {
+        return (0, 0);
+    }
+
+    let selected_index = selected_index.min(num_matches - 1);
+
+    // Scroll to keep the selected match visible
+    let scroll_offset = if selected_index < scroll_offset {
+        selected_index
+    } else if selected_index >= scroll_offset + viewport_height {
+        selected_index + 1 - viewport_height
+    } else {
+        scroll_offset
+    };
+
+    // Never scroll past the last match
+    (
+        selected_index,
+        scroll_offset.min(num_matches.saturating_sub(viewport_height)),
+    )
+}
+
 /// Manage the terminal UI state.
 pub struct TerminalUi {
     /// The full history of commands.
     pub history: Vec<CommandEntry>,
+    /// The unique commands of the history, searched on each keystroke.
+    pub index: SearchIndex,
     /// The list of commands matching the current search term.
-    matches: Vec<CommandEntry>,
+    matches: Vec<SearchResult>,
+    /// Every command of the index matching the last search term, narrowed down as the term grows.
+    candidates: Option<SearchCandidates>,
     /// The current search term entered by the user.
     input: String,
+    /// The byte position of the cursor in the search term.
+    cursor: usize,
     /// The index of the currently selected command in the matches list.
     selected_index: usize,
+    /// The index of the first match displayed in the viewport.
+    scroll_offset: usize,
+    /// The number of rows available to display matches, as of the last draw.
+    viewport_height: usize,
     /// The current search term (optional, used for initial search).
     term: Option<String>,
     /// The maximum number of results to display.
     num_results: usize,
+    /// The options used to match and rank commands.
+    search_options: SearchOptions,
+    /// The options controlling the UI behavior.
+    ui_options: UiOptions,
+    /// The way matches are listed.
+    view: ResultsView,
+    /// Labels describing the filters applied to the history.
+    filters: Vec<String>,
+    /// Whether the active filters are listed in the status line.
+    show_filters: bool,
+    /// The content of the preview pane, if displayed.
+    preview: Option<Preview>,
+    /// The manual pages read during the session, by command name.
+    man_pages: HashMap<String, Vec<String>>,
+    /// The files found during the session, by path, or `None` while still looked up.
+    found_files: HashMap<String, Option<Vec<String>>>,
+    /// The sending end given to the threads looking up files.
+    files_sender: Sender<(String, Vec<String>)>,
+    /// The files found by the lookup threads, by path.
+    files_receiver: Receiver<(String, Vec<String>)>,
+    /// The follower feeding new history entries, when following the history file.
+    follower: Option<HistoryFollower>,
+    /// The maximum number of history entries to keep.
+    max_history: usize,
+    /// The index of the match under the last left mouse button press.
+    mouse_down_index: Option<usize>,
+    /// The shell whose history file entries can be deleted from, if allowed.
+    shell: Option<Shell>,
+    /// The options to read the history again with F5, if allowed.
+    reload_options: Option<HistoryLoadOptions>,
+    /// A message about the last action, shown in the status line until the next key.
+    message: Option<String>,
     /// The standard output handle for rendering the UI.
     stdout: Stdout,
 }
@@ -48,23 +215,57 @@ impl TerminalUi {
     ///
     /// * `num_results`: Maximum number of results to display.
     /// * `history`: Vector of command entries from shell history.
+    /// * `search_options`: Options used to match and rank commands.
+    /// * `ui_options`: Options controlling the UI behavior.
     ///
-    pub fn new(num_results: usize, history: Vec<CommandEntry>) -> Result<Self> {
+    pub fn new(
+        num_results: usize,
+        history: Vec<CommandEntry>,
+        search_options: SearchOptions,
+        ui_options: UiOptions,
+    ) -> Result<Self> {
         debug!("Initialize UI");
 
-        terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+        terminal::enable_raw_mode().map_err(TermsearchError::terminal_setup("enable raw mode"))?;
         let mut stdout = stdout();
-        execute!(stdout, EnterAlternateScreen, Hide)
-            .context("Failed to enter alternate screen and hide cursor")?;
+        execute!(stdout, EnterAlternateScreen, Hide).map_err(TermsearchError::terminal_setup(
+            "enter alternate screen and hide cursor",
+        ))?;
+        if ui_options.mouse {
+            execute!(stdout, EnableMouseCapture)
+                .map_err(TermsearchError::terminal_setup("enable mouse capture"))?;
+        }
 
+        let (files_sender, files_receiver) = mpsc::channel();
         Ok(Self {
             stdout,
+            index: SearchIndex::new(&history, &search_options),
             history,
             matches: Vec::new(),
+            candidates: None,
             input: String::new(),
+            cursor: 0,
             selected_index: 0,
+            scroll_offset: 0,
+            viewport_height: 0,
             term: None,
             num_results,
+            search_options,
+            ui_options,
+            view: ResultsView::Scored,
+            filters: Vec::new(),
+            show_filters: true,
+            preview: None,
+            man_pages: HashMap::new(),
+            found_files: HashMap::new(),
+            files_sender,
+            files_receiver,
+            follower: None,
+            max_history: usize::MAX,
+            mouse_down_index: None,
+            shell: None,
+            reload_options: None,
+            message: None,
         })
     }
 
@@ -72,21 +273,78 @@ impl TerminalUi {
     ///
     /// # Arguments
     ///
-    /// * `initial_matches`: Vector of initial command entries to display.
+    /// * `initial_matches`: Vector of initial search results to display.
     ///
-    pub fn set_initial_results(&mut self, initial_matches: Vec<CommandEntry>) -> Result<()> {
+    pub fn set_initial_results(&mut self, initial_matches: Vec<SearchResult>) -> Result<()> {
         debug!("Set initial results, count: {}", initial_matches.len());
         self.matches = initial_matches;
-        self.selected_index = 0;
+        self.reset_selection();
         self.draw_matches()
     }
 
+    /// Set the labels of the filters applied to the history, shown in the status line.
+    ///
+    /// # Arguments
+    ///
+    /// * `filters`: A short label for each active filter.
+    ///
+    pub fn set_filters(&mut self, filters: Vec<String>) {
+        self.filters = filters;
+    }
+
+    /// Show a message in the status line until the next key.
+    ///
+    /// # Arguments
+    ///
+    /// * `message`: The message to show.
+    ///
+    pub fn set_message(&mut self, message: String) {
+        self.message = Some(message);
+    }
+
+    /// Follow the history file, adding new entries to the history as they are written.
+    ///
+    /// # Arguments
+    ///
+    /// * `follower`: The follower reading the history file.
+    /// * `max_history`: Maximum number of history entries to keep.
+    ///
+    pub fn follow(&mut self, follower: HistoryFollower, max_history: usize) {
+        self.follower = Some(follower);
+        self.max_history = max_history;
+    }
+
+    /// Allow deleting the selected command from the history file.
+    ///
+    /// # Arguments
+    ///
+    /// * `shell`: The shell whose history file is edited.
+    ///
+    pub fn allow_deletion(&mut self, shell: Shell) {
+        self.shell = Some(shell);
+    }
+
+    /// Allow reading the history again with F5.
+    ///
+    /// # Arguments
+    ///
+    /// * `options`: The options the history was read with.
+    ///
+    pub fn allow_reload(&mut self, options: HistoryLoadOptions) {
+        self.reload_options = Some(options);
+    }
+
     /// Clean up the terminal UI state.
     pub fn cleanup(&mut self) -> Result<()> {
         debug!("Cleanup UI");
-        terminal::disable_raw_mode().context("Failed to disable raw mode")?;
+        terminal::disable_raw_mode()
+            .map_err(TermsearchError::terminal_setup("disable raw mode"))?;
+        if self.ui_options.mouse {
+            execute!(self.stdout, DisableMouseCapture)
+                .map_err(TermsearchError::terminal_setup("disable mouse capture"))?;
+        }
         execute!(self.stdout, Show, ResetColor, LeaveAlternateScreen)
-            .context("Failed to restore terminal state")?;
+            .map_err(TermsearchError::terminal_setup("restore terminal state"))?;
         Ok(())
     }
 
@@ -101,6 +359,7 @@ impl TerminalUi {
 
         if let Some(term) = initial_term {
             self.input = term;
+            self.cursor = self.input.len();
             self.term = Some(self.input.clone());
         }
 
@@ -110,16 +369,37 @@ impl TerminalUi {
 
         // Main event loop
         loop {
-            if let Event::Key(key_event) = event::read()? {
-                if key_event.kind == KeyEventKind::Press {
-                    match self.handle_key_event(key_event)? {
-                        KeyAction::Select(command) => return Ok(Some(command)),
-                        KeyAction::Continue => {}
-                        KeyAction::Exit => {
-                            self.cleanup()?;
-                            return Ok(None);
-                        }
-                    }
+            // Check the followed history file and the file lookups while waiting for input
+            let pending_files = self.found_files.values().any(Option::is_none);
+            if (self.follower.is_some() || pending_files) && !event::poll(FOLLOW_POLL_INTERVAL)? {
+                self.poll_history()?;
+                self.receive_files()?;
+                continue;
+            }
+
+            let action = match event::read()? {
+                Event::Key(key_event) if key_event.kind == KeyEventKind::Press => {
+                    self.handle_key_event(key_event)?
+                }
+                Event::Mouse(mouse_event) if self.ui_options.mouse => {
+                    self.handle_mouse_event(mouse_event)?
+                }
+                Event::Resize(width, height) => {
+                    // Recompute the visible window for the new terminal size
+                    debug!("Terminal resized to {}x{}", width, height);
+                    queue!(self.stdout, terminal::Clear(terminal::ClearType::All))?;
+                    self.draw_matches()?;
+                    KeyAction::Continue
+                }
+                _ => KeyAction::Continue,
+            };
+
+            match action {
+                KeyAction::Select(command) => return Ok(Some(command)),
+                KeyAction::Continue => {}
+                KeyAction::Exit => {
+                    self.cleanup()?;
+                    return Ok(None);
                 }
             }
             // Add a small delay to reduce CPU usage
@@ -127,6 +407,64 @@ impl TerminalUi {
         }
     }
 
+    /// Add entries written to the followed history file and refresh the matches.
+    fn poll_history(&mut self) -> Result<()> {
+        let Some(follower) = &mut self.follower else {
+            return Ok(());
+        };
+
+        match follower.poll()? {
+            Some(HistoryUpdate::Appended(entries)) => {
+                debug!("Add {} followed history entries", entries.len());
+                self.history.extend(entries);
+                let excess = self.history.len().saturating_sub(self.max_history);
+                self.history.drain(..excess);
+            }
+            Some(HistoryUpdate::Rewritten(entries)) => {
+                debug!("Replace history with {} followed entries", entries.len());
+                self.history = entries;
+            }
+            None => return Ok(()),
+        }
+
+        self.rebuild_index();
+        self.update_matches();
+        self.draw_matches()
+    }
+
+    /// Store the files found by the lookup threads and redraw the preview showing them.
+    fn receive_files(&mut self) -> Result<()> {
+        let mut received = false;
+        while let Ok((path, files)) = self.files_receiver.try_recv() {
+            self.found_files.insert(path, Some(files));
+            received = true;
+        }
+
+        if received && self.preview == Some(Preview::Files) {
+            self.draw_matches()?;
+        }
+        Ok(())
+    }
+
+    /// Look up in the background the files of the selected command not found yet.
+    fn request_files(&mut self) {
+        let Some(result) = self.matches.get(self.selected_index) else {
+            return;
+        };
+
+        for path in extract_paths(&result.entry.command) {
+            if self.found_files.contains_key(&path) {
+                continue;
+            }
+            self.found_files.insert(path.clone(), None);
+            let sender = self.files_sender.clone();
+            thread::spawn(move || {
+                let files = find_files(&path);
+                let _ = sender.send((path, files));
+            });
+        }
+    }
+
     /// Handle a key event and return the appropriate action.
     ///
     /// # Arguments
@@ -134,6 +472,8 @@ impl TerminalUi {
     /// * `key_event`: The key event to handle.
     ///
     fn handle_key_event(&mut self, key_event: KeyEvent) -> Result<KeyAction> {
+        self.message = None;
+
         match key_event.code {
             // Exit handling
             KeyCode::Esc => {
@@ -149,23 +489,126 @@ impl TerminalUi {
                 Ok(KeyAction::Exit)
             }
 
+            // View toggle
+            KeyCode::Char('t') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+T pressed");
+                self.view = match self.view {
+                    ResultsView::Scored => ResultsView::Chronological,
+                    ResultsView::Chronological => ResultsView::Scored,
+                };
+                self.update_matches();
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // Regex mode toggle
+            KeyCode::Char('r') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+R pressed");
+                self.search_options.regex = !self.search_options.regex;
+                self.update_matches();
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // Filters summary toggle
+            KeyCode::Char('f') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+F pressed");
+                self.show_filters = !self.show_filters;
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // Selected command deletion
+            KeyCode::Char('x') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+X pressed");
+                self.delete_selected()?;
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // Cursor movement to the line edges
+            KeyCode::Char('a') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+A pressed");
+                self.cursor = 0;
+                self.draw_input_buffer()?;
+                Ok(KeyAction::Continue)
+            }
+            KeyCode::Char('e') if key_event.modifiers == KeyModifiers::CONTROL => {
+                debug!("Ctrl+E pressed");
+                self.cursor = self.input.len();
+                self.draw_input_buffer()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // Weight tuning
+            KeyCode::Char(c @ ('+' | '-'))
+                if self.ui_options.tune && key_event.modifiers.contains(KeyModifiers::ALT) =>
+            {
+                debug!("Alt+{} pressed", c);
+                let step = if c == '+' { TUNE_STEP } else { -TUNE_STEP };
+                self.adjust_weights(step);
+                self.update_matches();
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
             // Character input
             KeyCode::Char(c) => {
                 debug!("Character '{}' pressed", c);
-                self.input.push(c);
-                self.term = Some(self.input.clone());
-                self.update_matches();
-                self.draw_matches()?;
+                self.input.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+                self.edit_input()?;
                 Ok(KeyAction::Continue)
             }
 
             // Backspace handling
             KeyCode::Backspace => {
                 debug!("Backspace pressed");
-                self.input.pop();
-                self.term = Some(self.input.clone());
-                self.update_matches();
-                self.draw_matches()?;
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                    self.input.remove(self.cursor);
+                    self.edit_input()?;
+                }
+                Ok(KeyAction::Continue)
+            }
+
+            // Delete handling
+            KeyCode::Delete => {
+                debug!("Delete pressed");
+                if self.cursor < self.input.len() {
+                    self.input.remove(self.cursor);
+                    self.edit_input()?;
+                }
+                Ok(KeyAction::Continue)
+            }
+
+            // Cursor movement
+            KeyCode::Left => {
+                debug!("Left key pressed");
+                if let Some(c) = self.input[..self.cursor].chars().next_back() {
+                    self.cursor -= c.len_utf8();
+                }
+                self.draw_input_buffer()?;
+                Ok(KeyAction::Continue)
+            }
+            KeyCode::Right => {
+                debug!("Right key pressed");
+                if let Some(c) = self.input[self.cursor..].chars().next() {
+                    self.cursor += c.len_utf8();
+                }
+                self.draw_input_buffer()?;
+                Ok(KeyAction::Continue)
+            }
+            KeyCode::Home => {
+                debug!("Home key pressed");
+                self.cursor = 0;
+                self.draw_input_buffer()?;
+                Ok(KeyAction::Continue)
+            }
+            KeyCode::End => {
+                debug!("End key pressed");
+                self.cursor = self.input.len();
+                self.draw_input_buffer()?;
                 Ok(KeyAction::Continue)
             }
 
@@ -193,11 +636,35 @@ impl TerminalUi {
                 Ok(KeyAction::Continue)
             }
 
+            // Manual page toggle
+            KeyCode::F(1) if self.ui_options.man => {
+                debug!("F1 key pressed");
+                self.toggle_preview(Preview::Man);
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // File search toggle
+            KeyCode::F(2) if self.ui_options.find_files => {
+                debug!("F2 key pressed");
+                self.toggle_preview(Preview::Files);
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
+            // History reload
+            KeyCode::F(5) if self.reload_options.is_some() => {
+                debug!("F5 key pressed");
+                self.reload_history();
+                self.draw_matches()?;
+                Ok(KeyAction::Continue)
+            }
+
             // Command selection
             KeyCode::Enter => {
                 debug!("Enter key pressed");
-                if let Some(command_entry) = self.matches.get(self.selected_index) {
-                    Ok(KeyAction::Select(command_entry.command.clone()))
+                if let Some(result) = self.matches.get(self.selected_index) {
+                    Ok(KeyAction::Select(result.entry.command.clone()))
                 } else {
                     Ok(KeyAction::Continue)
                 }
@@ -211,37 +678,477 @@ impl TerminalUi {
         }
     }
 
+    /// Handle a mouse event and return the appropriate action.
+    ///
+    /// Pressing the left button on a result moves the selection to it, and
+    /// releasing it on the same result accepts it.
+    ///
+    /// # Arguments
+    ///
+    /// * `mouse_event`: The mouse event to handle.
+    ///
+    fn handle_mouse_event(&mut self, mouse_event: MouseEvent) -> Result<KeyAction> {
+        let index = self.row_to_index(mouse_event.row);
+
+        match mouse_event.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                debug!("Mouse pressed on row {}", mouse_event.row);
+                self.mouse_down_index = index;
+                if let Some(index) = index {
+                    self.selected_index = index;
+                    self.draw_matches()?;
+                }
+                Ok(KeyAction::Continue)
+            }
+            MouseEventKind::Up(MouseButton::Left) => {
+                debug!("Mouse released on row {}", mouse_event.row);
+                let pressed_index = self.mouse_down_index.take();
+                match index {
+                    Some(index) if pressed_index == Some(index) => {
+                        Ok(KeyAction::Select(self.matches[index].entry.command.clone()))
+                    }
+                    _ => Ok(KeyAction::Continue),
+                }
+            }
+            _ => Ok(KeyAction::Continue),
+        }
+    }
+
+    /// Map a terminal row to the index of the match displayed on it.
+    ///
+    /// # Arguments
+    ///
+    /// * `row`: The terminal row.
+    ///
+    /// # Returns
+    ///
+    /// The index of the match, or `None` if the row does not display a match.
+    ///
+    fn row_to_index(&self, row: u16) -> Option<usize> {
+        let visible_row = (row as usize).checked_sub(1)?;
+        if visible_row >= self.viewport_height {
+            return None;
+        }
+
+        let index = visible_row + self.scroll_offset;
+        (index < self.matches.len()).then_some(index)
+    }
+
+    /// Delete the selected command from the history and the history file.
+    ///
+    /// In the chronological view only the selected entry is deleted, otherwise
+    /// every command counted together with it is, as the search options group
+    /// variants by shape, normalization, case or alias.
+    fn delete_selected(&mut self) -> Result<()> {
+        let (Some(shell), Some(selected)) = (self.shell, self.matches.get(self.selected_index))
+        else {
+            return Ok(());
+        };
+        let selected = selected.entry.clone();
+        debug!("Delete command: {}", selected.command);
+
+        let deleted = if self.view == ResultsView::Chronological {
+            delete_history_entries(shell, &mut self.history, |entry| {
+                entry.command == selected.command && entry.source == selected.source
+            })
+        } else {
+            let options = &self.search_options;
+            let key = frequency_key(&selected.command, options).into_owned();
+            delete_history_entries(shell, &mut self.history, |entry| {
+                frequency_key(&entry.command, options) == key
+            })
+        };
+
+        match deleted {
+            Ok(count) => {
+                self.message = Some(format!("deleted {} history entries", count));
+
+                // Follow the rewritten history file from its new end
+                if let Some(follower) = &self.follower {
+                    self.follower = Some(follower.reopen()?);
+                }
+            }
+            Err(e) => {
+                debug!("Failed to delete command: {:#}", e);
+                self.message = Some(format!("delete failed: {:#}", e));
+            }
+        }
+
+        // Refresh the matches, keeping the selection in place
+        self.rebuild_index();
+        let selected_index = self.selected_index;
+        self.update_matches();
+        self.selected_index = selected_index;
+
+        Ok(())
+    }
+
+    /// Read the history again and refresh the matches.
+    ///
+    /// The selection stays on the selected command if it still matches. A
+    /// failure to read the history is reported in the status line and leaves
+    /// the current history in place.
+    fn reload_history(&mut self) {
+        let Some(options) = &self.reload_options else {
+            return;
+        };
+
+        let history = match read_history(options) {
+            Ok((history, _)) => history,
+            Err(e) => {
+                debug!("Failed to reload history: {:#}", e);
+                self.message = Some(format!("reload failed: {:#}", e));
+                return;
+            }
+        };
+        debug!("Reloaded {} history entries", history.len());
+        self.message = Some(format!("reloaded {} history entries", history.len()));
+
+        // Follow the history file from its current end, past the entries just read
+        if let Some(follower) = &self.follower {
+            match follower.reopen() {
+                Ok(follower) => self.follower = Some(follower),
+                Err(e) => debug!("Failed to follow reloaded history: {:#}", e),
+            }
+        }
+
+        let selected = self
+            .matches
+            .get(self.selected_index)
+            .map(|result| result.entry.command.clone());
+        self.history = history;
+        self.rebuild_index();
+        self.update_matches();
+
+        // Keep the selection on the same command
+        if let Some(position) = selected.and_then(|command| {
+            self.matches
+                .iter()
+                .position(|result| result.entry.command == command)
+        }) {
+            self.selected_index = position;
+        }
+    }
+
+    /// Search again after the search term was edited.
+    fn edit_input(&mut self) -> Result<()> {
+        self.term = Some(self.input.clone());
+        self.update_matches();
+        self.draw_matches()
+    }
+
     /// Update the matches based on the current search term.
     fn update_matches(&mut self) {
         debug!("Update matches");
 
-        self.matches = if let Some(term) = &self.term {
+        self.matches = if self.view == ResultsView::Chronological {
+            self.chronological_matches()
+        } else if let Some(term) = &self.term {
             if !term.is_empty() {
-                search_commands(term, &self.history, self.num_results)
+                self.index.narrow_search(
+                    term,
+                    self.num_results,
+                    &self.search_options,
+                    &mut self.candidates,
+                )
             } else {
-                get_frequent_commands(&self.history, self.num_results)
+                self.index.frequent(self.num_results, &self.search_options)
+            }
+        } else {
+            self.index.frequent(self.num_results, &self.search_options)
+        };
+        if let Some(best) = self.matches.first() {
+            debug!("Best match scored {}", best.score);
+        }
+
+        self.reset_selection();
+    }
+
+    /// Index the history again, after it changed.
+    fn rebuild_index(&mut self) {
+        self.index = SearchIndex::new(&self.history, &self.search_options);
+        // The positions of the commands matching the last search term changed
+        self.candidates = None;
+    }
+
+    /// List every history entry containing the search term, most recent first.
+    fn chronological_matches(&self) -> Vec<SearchResult> {
+        let Some(matcher) = self.term_matcher() else {
+            return Vec::new();
+        };
+
+        self.history
+            .iter()
+            .rev()
+            .filter(|entry| matcher.contained_in(&entry.command))
+            .map(|entry| SearchResult {
+                entry: entry.clone(),
+                score: 0.0,
+                components: ScoreComponents::default(),
+                match_ranges: matcher
+                    .matches(&entry.command)
+                    .map(|term_match| term_match.ranges)
+                    .unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Get the matcher of the current search term.
+    ///
+    /// # Returns
+    ///
+    /// The matcher, or `None` if the term is not a valid regular expression.
+    ///
+    fn term_matcher(&self) -> Option<TermMatcher> {
+        TermMatcher::new(
+            self.term.as_deref().unwrap_or_default(),
+            self.search_options.regex,
+            self.search_options.case_matching(),
+            self.search_options.fuzzy,
+        )
+    }
+
+    /// Get the expansion of a command's alias when only the expansion matches the term.
+    ///
+    /// # Arguments
+    ///
+    /// * `command`: The displayed command.
+    /// * `matcher`: The matcher of the current search term.
+    ///
+    /// # Returns
+    ///
+    /// The expanded command, or `None` if it is not an alias or its expansion does not match.
+    ///
+    fn alias_expansion(&self, command: &str, matcher: Option<&TermMatcher>) -> Option<String> {
+        match expand_alias(command, &self.search_options.aliases) {
+            Cow::Owned(expanded) if matcher?.matches(&expanded).is_some() => Some(expanded),
+            _ => None,
+        }
+    }
+
+    /// Shift the balance between the recency and frequency weights.
+    ///
+    /// # Arguments
+    ///
+    /// * `step`: Share of the total weight moved to the recency weight (and
+    ///   removed from the frequency weight).
+    ///
+    fn adjust_weights(&mut self, step: f32) {
+        if !self.search_options.shift_recency_balance(step) {
+            self.message = Some("ranking by match only, no weights to tune".to_string());
+            return;
+        }
+        debug!(
+            "Adjust weights: recency {:.2}, frequency {:.2}",
+            self.search_options.recency_weight, self.search_options.frequency_weight
+        );
+    }
+
+    /// Show a preview, or hide it if it is already shown.
+    ///
+    /// # Arguments
+    ///
+    /// * `preview`: The preview to toggle.
+    ///
+    fn toggle_preview(&mut self, preview: Preview) {
+        self.preview = if self.preview == Some(preview) {
+            None
+        } else {
+            Some(preview)
+        };
+    }
+
+    /// Draw the preview of the selected command.
+    ///
+    /// # Arguments
+    ///
+    /// * `top`: The first row of the preview pane.
+    /// * `pane_height`: The number of rows of the pane.
+    /// * `width`: The terminal width.
+    ///
+    fn draw_preview_pane(&mut self, top: u16, pane_height: usize, width: u16) -> Result<()> {
+        let Some(command) = self
+            .matches
+            .get(self.selected_index)
+            .map(|result| result.entry.command.clone())
+        else {
+            return Ok(());
+        };
+
+        let (title, lines) = match self.preview {
+            Some(Preview::Man) => {
+                let Some(name) = command.split_whitespace().next() else {
+                    return Ok(());
+                };
+                let lines = self
+                    .man_pages
+                    .entry(name.to_string())
+                    .or_insert_with(|| read_man_page(name, width));
+                (format!("man {}", name), lines.clone())
+            }
+            Some(Preview::Files) => {
+                let mut lines = Vec::new();
+                for path in extract_paths(&command) {
+                    lines.push(format!("{}:", path));
+                    match self.found_files.get(&path) {
+                        Some(Some(files)) => {
+                            lines.extend(files.iter().map(|file| format!("  {}", file)))
+                        }
+                        _ => lines.push("  Searching...".to_string()),
+                    }
+                }
+                if lines.is_empty() {
+                    lines.push("No paths in the selected command".to_string());
+                }
+                ("files".to_string(), lines)
+            }
+            None => return Ok(()),
+        };
+
+        let header = format!("── {} ", title);
+        queue!(
+            self.stdout,
+            cursor::MoveTo(0, top),
+            SetForegroundColor(Color::DarkGrey),
+            Print(format!("{:─<width$}", header, width = width as usize)),
+            ResetColor
+        )?;
+
+        for (row, line) in lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .take(pane_height.saturating_sub(1))
+            .enumerate()
+        {
+            queue!(
+                self.stdout,
+                cursor::MoveTo(0, top + 1 + row as u16),
+                Print(line.chars().take(width as usize).collect::<String>())
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the text of the status line, if there is anything to show.
+    fn status_line(&self) -> Option<String> {
+        let mut parts = Vec::new();
+
+        if let Some(message) = &self.message {
+            parts.push(message.clone());
+        }
+        if let Some(metadata) = self
+            .matches
+            .get(self.selected_index)
+            .and_then(|result| result.entry.metadata.as_ref())
+        {
+            if let Some(exit_status) = metadata.exit_status {
+                parts.push(format!("exit:{}", exit_status));
             }
+            if let Some(duration) = metadata.duration {
+                parts.push(format!("took:{:.1}s", duration.as_secs_f32()));
+            }
+            if let Some(cwd) = &metadata.cwd {
+                parts.push(format!("cwd:{}", cwd));
+            }
+        }
+        if self.view == ResultsView::Chronological {
+            parts.push("view: chronological".to_string());
+        }
+        if self.show_filters && !self.filters.is_empty() {
+            parts.push(self.filters.join(" "));
+        }
+        if self.ui_options.tune {
+            parts.push(format!(
+                "recency {:.2} / frequency {:.2}",
+                self.search_options.recency_weight, self.search_options.frequency_weight
+            ));
+        }
+
+        if parts.is_empty() {
+            None
         } else {
-            get_frequent_commands(&self.history, self.num_results)
+            Some(parts.join("  "))
+        }
+    }
+
+    /// Draw the status line at the bottom of the terminal.
+    fn draw_status_line(&mut self) -> Result<()> {
+        let Some(status) = self.status_line() else {
+            return Ok(());
+        };
+        let (width, height) = terminal::size()?;
+
+        queue!(
+            self.stdout,
+            cursor::MoveTo(0, height.saturating_sub(1)),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            SetForegroundColor(Color::DarkGrey),
+            Print(status.chars().take(width as usize).collect::<String>()),
+            ResetColor
+        )?;
+
+        Ok(())
+    }
+
+    /// Move the selection to its initial position in the matches.
+    fn reset_selection(&mut self) {
+        self.selected_index = match self.ui_options.initial_selection {
+            InitialSelection::First => 0,
+            InitialSelection::Last => self.matches.len().saturating_sub(1),
         };
+        self.scroll_offset = 0;
+    }
 
-        self.selected_index = 0;
+    /// Clamp the selection and scroll offset to the matches and viewport.
+    ///
+    /// # Arguments
+    ///
+    /// * `viewport_height`: The number of rows available to display matches.
+    ///
+    fn clamp_selection(&mut self, viewport_height: usize) {
+        (self.selected_index, self.scroll_offset) = clamp_selection(
+            self.selected_index,
+            self.scroll_offset,
+            self.matches.len(),
+            viewport_height,
+        );
     }
 
-    /// Draw the input buffer with the current search term.
+    /// Draw the input buffer with the current search term, and place the cursor in it.
     fn draw_input_buffer(&mut self) -> Result<()> {
         debug!("Draw input buffer");
         let (width, _) = terminal::size()?;
 
+        // Show the mode in the prompt, and an invalid regex in red
+        let prompt = if self.search_options.regex {
+            "regex> "
+        } else {
+            "> "
+        };
+        let input_color = if self.term_matcher().is_some() {
+            Color::Reset
+        } else {
+            Color::Red
+        };
+
+        // Calculate the cursor column after the prompt
+        let column = prompt.len() + self.input[..self.cursor].chars().count();
+
         queue!(
             self.stdout,
             cursor::MoveTo(0, 0),
             terminal::Clear(terminal::ClearType::CurrentLine),
+            Print(prompt),
+            SetForegroundColor(input_color),
             Print(format!(
                 "{:width$}",
-                format!("> {}", self.input),
-                width = width as usize
-            ))
+                self.input,
+                width = (width as usize).saturating_sub(prompt.len())
+            )),
+            ResetColor,
+            cursor::MoveTo(column.min(width.saturating_sub(1) as usize) as u16, 0),
+            Show
         )?;
         self.stdout.flush()?;
 
@@ -251,7 +1158,20 @@ impl TerminalUi {
     /// Draw the matches in the terminal with highlighting.
     fn draw_matches(&mut self) -> Result<()> {
         debug!("Draw matches");
-        let (_, height) = terminal::size()?;
+        let (width, height) = terminal::size()?;
+
+        // Hide the cursor while it moves through the matches
+        queue!(self.stdout, Hide)?;
+        let status_height = u16::from(self.status_line().is_some());
+        let available_height = height.saturating_sub(1 + status_height) as usize;
+        let preview_height = if self.preview.is_some() {
+            available_height / 2
+        } else {
+            0
+        };
+        let viewport_height = available_height - preview_height;
+        self.viewport_height = viewport_height;
+        self.clamp_selection(viewport_height);
 
         // Clear existing matches
         for i in 0..height {
@@ -262,11 +1182,62 @@ impl TerminalUi {
             )?;
         }
 
-        // Draw matches with highlighting
-        for (i, command_entry) in self.matches.iter().enumerate() {
+        // Limit the rendered width of commands
+        let render_width = self
+            .ui_options
+            .max_width
+            .map_or(width, |max_width| max_width.min(width)) as usize;
+
+        // Reserve a right-aligned column for the time since each visible command was run
+        let now = Utc::now();
+        let ages: Vec<String> = self
+            .matches
+            .iter()
+            .skip(self.scroll_offset)
+            .take(viewport_height)
+            .map(|result| relative_time(result.entry.timestamp, now))
+            .collect();
+        let age_width = ages.iter().map(|age| age.len()).max().unwrap_or(0);
+        let show_ages = render_width >= MIN_WIDTH_FOR_AGES;
+        let mut command_width = if show_ages {
+            render_width - age_width - 1
+        } else {
+            render_width
+        };
+
+        // Reserve another column for the scores, left of the ages, when ranked by score
+        let scores: Vec<String> = self
+            .matches
+            .iter()
+            .skip(self.scroll_offset)
+            .take(viewport_height)
+            .map(format_score)
+            .collect();
+        let score_width = scores.iter().map(|score| score.len()).max().unwrap_or(0);
+        let show_scores = self.ui_options.show_scores
+            && self.view == ResultsView::Scored
+            && render_width >= MIN_WIDTH_FOR_SCORES;
+        if show_scores {
+            command_width -= score_width + 1;
+        }
+
+        // Build the matcher once for every highlighted match
+        let matcher = self.term_matcher();
+
+        // Draw visible matches with highlighting
+        for (((i, result), age), score) in self
+            .matches
+            .iter()
+            .enumerate()
+            .skip(self.scroll_offset)
+            .take(viewport_height)
+            .zip(&ages)
+            .zip(&scores)
+        {
+            let row = (i - self.scroll_offset + 1) as u16;
             queue!(
                 self.stdout,
-                cursor::MoveTo(0, (i + 1) as u16),
+                cursor::MoveTo(0, row),
                 SetForegroundColor(if i == self.selected_index {
                     Color::Black
                 } else {
@@ -279,40 +1250,100 @@ impl TerminalUi {
                 }),
             )?;
 
-            // If there's a search term, highlight matching parts
+            // Show multiline commands on a single row
+            let display = result.entry.command.replace('\n', "↵");
+            let command = truncate_to_width(&display, command_width);
+
+            // If there's a search term, highlight the parts it matched
             if let Some(term) = &self.term {
-                let command = &command_entry.command;
-                if let Some(match_start) = command.to_lowercase().find(&term.to_lowercase()) {
-                    let match_end = match_start + term.len();
+                let ranges = display_ranges(&result.entry.command, &result.match_ranges);
+
+                if !ranges.is_empty() {
+                    // Only highlight the part of the command left after truncation
+                    let visible_len = match &command {
+                        Cow::Borrowed(command) => command.len(),
+                        Cow::Owned(command) => command.len() - '…'.len_utf8(),
+                    };
+                    let normal_color = if i == self.selected_index {
+                        Color::Black
+                    } else {
+                        Color::Reset
+                    };
 
-                    // Print before match
-                    queue!(self.stdout, Print(&command[..match_start]))?;
+                    let mut printed = 0;
+                    for range in ranges {
+                        let (start, end) =
+                            (range.start.min(visible_len), range.end.min(visible_len));
 
-                    // Print match with highlight
+                        // Print before match, then match with highlight
+                        queue!(
+                            self.stdout,
+                            Print(&command[printed..start]),
+                            SetForegroundColor(Color::Yellow),
+                            Print(&command[start..end]),
+                            SetForegroundColor(normal_color),
+                        )?;
+                        printed = end;
+                    }
+
+                    // Print after match
+                    queue!(self.stdout, Print(&command[printed..]))?;
+                } else if let Some(expanded) = self.alias_expansion(&command, matcher.as_ref()) {
+                    // Show the expansion of commands matched through their alias
                     queue!(
                         self.stdout,
-                        SetForegroundColor(Color::Yellow),
-                        Print(&command[match_start..match_end]),
-                        SetForegroundColor(if i == self.selected_index {
-                            Color::Black
-                        } else {
-                            Color::Reset
-                        }),
+                        Print(&command),
+                        SetForegroundColor(Color::DarkGrey),
+                        Print(format!(" ({})", expanded)),
+                    )?;
+                } else if matches_abbreviation(term, &command, &self.search_options) {
+                    // Mark commands matched through an abbreviation
+                    queue!(
+                        self.stdout,
+                        Print(&command),
+                        SetForegroundColor(Color::DarkGrey),
+                        Print(format!(" ({})", term.trim())),
                     )?;
-
-                    // Print after match
-                    queue!(self.stdout, Print(&command[match_end..]))?;
                 } else {
-                    queue!(self.stdout, Print(&command_entry.command))?;
+                    queue!(self.stdout, Print(&command))?;
                 }
             } else {
-                queue!(self.stdout, Print(&command_entry.command))?;
+                queue!(self.stdout, Print(&command))?;
+            }
+
+            // Print the score right-aligned left of the ages
+            if show_scores {
+                queue!(
+                    self.stdout,
+                    cursor::MoveTo((command_width + 1 + score_width - score.len()) as u16, row),
+                    SetForegroundColor(Color::DarkGrey),
+                    Print(score),
+                )?;
+            }
+
+            // Print when the command was run at the right edge
+            if show_ages {
+                queue!(
+                    self.stdout,
+                    cursor::MoveTo((render_width - age.len()) as u16, row),
+                    SetForegroundColor(Color::DarkGrey),
+                    Print(age),
+                )?;
             }
 
             queue!(self.stdout, ResetColor)?;
         }
 
-        // Redraw input buffer
+        // Draw the preview below the matches
+        if preview_height > 0 {
+            if self.preview == Some(Preview::Files) {
+                self.request_files();
+            }
+            self.draw_preview_pane(1 + viewport_height as u16, preview_height, width)?;
+        }
+
+        // Redraw status line and input buffer
+        self.draw_status_line()?;
         self.draw_input_buffer()?;
         self.stdout.flush()?;
 
@@ -320,8 +1351,157 @@ impl TerminalUi {
     }
 }
 
+/// Format the score of a match with its parts, such as `0.734 m1.00 r0.52 f0.33`.
+///
+/// # Arguments
+///
+/// * `result`: The match.
+///
+/// # Returns
+///
+/// The total score, followed by the match, recency and frequency scores.
+///
+fn format_score(result: &SearchResult) -> String {
+    format!(
+        "{:.3} m{:.2} r{:.2} f{:.2}",
+        result.score,
+        result.components.match_score,
+        result.components.recency,
+        result.components.frequency
+    )
+}
+
+/// Format the time elapsed since a timestamp, such as `5m ago` or `3d ago`.
+///
+/// # Arguments
+///
+/// * `timestamp`: The timestamp.
+/// * `now`: The current time.
+///
+/// # Returns
+///
+/// The elapsed time in its largest whole unit.
+///
+fn relative_time(timestamp: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - timestamp).num_seconds().max(0);
+
+    match seconds {
+        0..60 => format!("{}s ago", seconds),
+        60..3600 => format!("{}m ago", seconds / 60),
+        3600..86400 => format!("{}h ago", seconds / 3600),
+        86400..31536000 => format!("{}d ago", seconds / 86400),
+        _ => format!("{}y ago", seconds / 31536000),
+    }
+}
+
+/// Shift the matched byte ranges of a command to its displayed form, where line
+/// breaks are shown as `↵`.
+///
+/// # Arguments
+///
+/// * `command`: The command.
+/// * `ranges`: The byte ranges matched in the command.
+///
+/// # Returns
+///
+/// The byte ranges in the displayed command.
+///
+fn display_ranges(command: &str, ranges: &[Range<usize>]) -> Vec<Range<usize>> {
+    let shift = '↵'.len_utf8() - 1;
+    let shifted = |pos: usize| pos + shift * command[..pos].matches('\n').count();
+    ranges
+        .iter()
+        .map(|range| shifted(range.start)..shifted(range.end))
+        .collect()
+}
+
+/// Truncate text to a number of columns, ending it with an ellipsis when cut.
+///
+/// # Arguments
+///
+/// * `text`: The text to truncate.
+/// * `width`: The maximum number of columns.
+///
+/// # Returns
+///
+/// The text, truncated if it does not fit.
+///
+fn truncate_to_width(text: &str, width: usize) -> Cow<'_, str> {
+    if text.chars().count() <= width {
+        return Cow::Borrowed(text);
+    }
+
+    let mut truncated: String = text.chars().take(width.saturating_sub(1)).collect();
+    if width > 0 {
+        truncated.push('…');
+    }
+    Cow::Owned(truncated)
+}
+
 impl Drop for TerminalUi {
     fn drop(&mut self) {
         let _ = self.cleanup();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clamp_selection_to_few_matches() {
+        // Fewer matches than rows: nothing to scroll
+        assert_eq!(clamp_selection(2, 0, 3, 40), (2, 0));
+        assert_eq!(clamp_selection(9, 5, 3, 40), (2, 0));
+        assert_eq!(clamp_selection(0, 0, 1, 40), (0, 0));
+        assert_eq!(clamp_selection(4, 2, 0, 40), (0, 0));
+        assert_eq!(clamp_selection(4, 2, 10, 0), (0, 0));
+    }
+
+    #[test]
+    fn clamp_selection_to_viewport() {
+        // Selection below or above the viewport scrolls to it
+        assert_eq!(clamp_selection(12, 0, 20, 5), (12, 8));
+        assert_eq!(clamp_selection(3, 8, 20, 5), (3, 3));
+        // No blank rows after the last match once the viewport grows
+        assert_eq!(clamp_selection(19, 15, 20, 10), (19, 10));
+    }
+
+    /// Build a match with the given score parts.
+    fn scored(score: f32, match_score: f32, recency: f32, frequency: f32) -> SearchResult {
+        SearchResult {
+            entry: CommandEntry {
+                command: "ls".to_string(),
+                timestamp: Utc::now(),
+                source: None,
+                metadata: None,
+                count: 1,
+            },
+            score,
+            components: ScoreComponents {
+                match_score,
+                recency,
+                frequency,
+            },
+            match_ranges: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn format_scores_at_fixed_width() {
+        assert_eq!(
+            format_score(&scored(0.7344, 1.0, 0.5151, 0.3333)),
+            "0.734 m1.00 r0.52 f0.33"
+        );
+        assert_eq!(
+            format_score(&scored(0.0, 0.0, 0.0, 0.0)),
+            "0.000 m0.00 r0.00 f0.00"
+        );
+        assert_eq!(
+            format_score(&scored(1.0, 1.0, 1.0, 1.0)),
+            "1.000 m1.00 r1.00 f1.00"
+        );
+        // Every score takes the same width, to be right-aligned
+        assert_eq!(format_score(&scored(0.12345, 0.995, 0.004, 0.5)).len(), 23);
+    }
+}
@@ -0,0 +1,24 @@
+# Function to trigger the termsearch search functionality
+function termsearch-search
+    # Create a temporary file for termsearch output
+    set -l temp_file (mktemp -t termsearch.XXXXXX)
+
+    # Run termsearch search, passing the buffer up to the cursor and output file
+    termsearch search --shell fish -o $temp_file (commandline -c)
+
+    # Read the command line from the temporary file, where the lines after
+    # the first one continue a multiline command
+    set -l commandline (string replace -r '^commandline\t' '' < $temp_file | string collect)
+
+    # Clean up the temporary file
+    command rm -f $temp_file
+
+    # Update the buffer if a command was selected
+    if test -n "$commandline"
+        commandline -r -- $commandline
+    end
+    commandline -f repaint
+end
+
+# Bind Ctrl+r to the termsearch-search function
+bind \cr termsearch-search